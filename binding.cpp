@@ -6,34 +6,65 @@
 
 #include <cassert>
 #include <cinttypes>
+#include <cstdarg>
 #include <cmath>
 #include <cstdio>
 #include <cstring>
 #include <fstream>
 #include <iostream>
+#include <mutex>
 #include <string>
 #include <vector>
-#include <sstream>
-#include <regex>
-#if defined(__unix__) || (defined(__APPLE__) && defined(__MACH__))
-#include <signal.h>
-#include <unistd.h>
-#elif defined(_WIN32)
-#define WIN32_LEAN_AND_MEAN
-#define NOMINMAX
-#include <windows.h>
-#include <signal.h>
-#endif
 
-#if defined(__unix__) || (defined(__APPLE__) && defined(__MACH__)) || defined(_WIN32)
-void sigint_handler(int signo)
+// set while llama_binding_quantize runs, llama.cpp logs the progress on the calling thread
+static thread_local void *quantize_progress = nullptr;
+
+// llama.cpp's log and the binding's own messages both end up in the log crate on the Rust side
+static void binding_log_callback(enum ggml_log_level level, const char *text, void *user_data)
+{
+    (void)user_data;
+
+    // llama.cpp only reports quantization progress through its log, one "[ i/ n] tensor" line
+    // per tensor
+    int current, total;
+    if (quantize_progress != nullptr && sscanf(text, "[%d/%d]", &current, &total) == 2)
+    {
+        quantizeProgressCallback(quantize_progress, current, total);
+    }
+
+    logCallback((int)level, "llama_cpp", text);
+}
+
+// the callback is process wide, it is installed once and never swapped
+static void install_log_callback()
+{
+    static std::once_flag once;
+    std::call_once(once, []() { llama_log_set(binding_log_callback, nullptr); });
+}
+
+static void binding_log(int level, const char *format, ...)
 {
-    if (signo == SIGINT)
+    va_list args;
+    va_start(args, format);
+    va_list args_copy;
+    va_copy(args_copy, args);
+
+    std::vector<char> buf(256);
+    int n = vsnprintf(buf.data(), buf.size(), format, args);
+    if (n >= (int)buf.size())
+    {
+        buf.resize(n + 1);
+        vsnprintf(buf.data(), buf.size(), format, args_copy);
+    }
+
+    va_end(args_copy);
+    va_end(args);
+
+    if (n >= 0)
     {
-        _exit(130);
+        logCallback(level, "llama_cpp_rs::binding", buf.data());
     }
 }
-#endif
 
 static std::string llama_token_to_str(const struct llama_context * ctx, llama_token token) {
     std::vector<char> result(8, 0);
@@ -50,6 +81,89 @@ static std::string llama_token_to_str(const struct llama_context * ctx, llama_to
 }
 
 
+static llama_token sample_token(llama_context *ctx, const gpt_params *params_p, const float *logits, const llama_token *last_tokens, int n_last, float *mirostat_mu)
+{
+    const llama_model *model = llama_get_model(ctx);
+    const int n_ctx = llama_n_ctx(ctx);
+    const int n_vocab = llama_n_vocab(model);
+
+    const float temp = params_p->sparams.temp;
+    const int32_t top_k = params_p->sparams.top_k <= 0 ? n_vocab : params_p->sparams.top_k;
+    const float top_p = params_p->sparams.top_p;
+    const float tfs_z = params_p->sparams.tfs_z;
+    const float typical_p = params_p->sparams.typical_p;
+    const int32_t repeat_last_n = params_p->sparams.penalty_last_n < 0 ? n_ctx : params_p->sparams.penalty_last_n;
+    const float repeat_penalty = params_p->sparams.penalty_repeat;
+    const float alpha_presence = params_p->sparams.penalty_present;
+    const float alpha_frequency = params_p->sparams.penalty_freq;
+    const int mirostat = params_p->sparams.mirostat;
+    const float mirostat_tau = params_p->sparams.mirostat_tau;
+    const float mirostat_eta = params_p->sparams.mirostat_eta;
+    const bool penalize_nl = params_p->sparams.penalize_nl;
+
+    // the logits are left untouched so the same row can be sampled more than once
+    std::vector<llama_token_data> candidates;
+    candidates.reserve(n_vocab);
+    for (llama_token token_id = 0; token_id < n_vocab; token_id++)
+    {
+        candidates.emplace_back(llama_token_data{token_id, logits[token_id], 0.0f});
+    }
+
+    // Apply params_p->logit_bias map
+    for (auto it = params_p->sparams.logit_bias.begin(); it != params_p->sparams.logit_bias.end(); it++)
+    {
+        candidates[it->first].logit += it->second;
+    }
+
+    if (params_p->ignore_eos)
+    {
+        candidates[llama_token_eos(model)].logit = -INFINITY;
+    }
+
+    const llama_token nl = llama_token_nl(model);
+    const float nl_logit = candidates[nl].logit;
+
+    llama_token_data_array candidates_p = {candidates.data(), candidates.size(), false};
+
+    // Apply penalties
+    auto last_n_repeat = std::min(std::min(n_last, repeat_last_n), n_ctx);
+    llama_sample_repetition_penalties(ctx, &candidates_p,
+                                      last_tokens + n_last - last_n_repeat,
+                                      last_n_repeat, repeat_penalty, alpha_frequency, alpha_presence);
+    if (!penalize_nl)
+    {
+        // the candidates are still in token order, penalties don't sort them
+        candidates_p.data[nl].logit = nl_logit;
+    }
+
+    if (temp <= 0)
+    {
+        // Greedy sampling
+        return llama_sample_token_greedy(ctx, &candidates_p);
+    }
+
+    if (mirostat == 1)
+    {
+        const int mirostat_m = 100;
+        llama_sample_temperature(ctx, &candidates_p, temp);
+        return llama_sample_token_mirostat(ctx, &candidates_p, mirostat_tau, mirostat_eta, mirostat_m, mirostat_mu);
+    }
+
+    if (mirostat == 2)
+    {
+        llama_sample_temperature(ctx, &candidates_p, temp);
+        return llama_sample_token_mirostat_v2(ctx, &candidates_p, mirostat_tau, mirostat_eta, mirostat_mu);
+    }
+
+    // Temperature sampling
+    llama_sample_top_k(ctx, &candidates_p, top_k, 1);
+    llama_sample_tail_free(ctx, &candidates_p, tfs_z, 1);
+    llama_sample_typical(ctx, &candidates_p, typical_p, 1);
+    llama_sample_top_p(ctx, &candidates_p, top_p, 1);
+    llama_sample_temperature(ctx, &candidates_p, temp);
+    return llama_sample_token(ctx, &candidates_p);
+}
+
 int get_embeddings(void *params_ptr, void *state_pr, float *res_embeddings)
 {
     gpt_params *params_p = (gpt_params *)params_ptr;
@@ -80,7 +194,7 @@ int get_embeddings(void *params_ptr, void *state_pr, float *res_embeddings)
     {
         if (llama_eval(ctx, embd_inp.data(), embd_inp.size(), n_past))
         {
-            fprintf(stderr, "%s : failed to eval\n", __func__);
+            binding_log(LLAMA_BINDING_LOG_ERROR, "%s: failed to eval\n", __func__);
             return 1;
         }
     }
@@ -99,7 +213,7 @@ int get_embeddings(void *params_ptr, void *state_pr, float *res_embeddings)
     return 0;
 }
 
-int get_token_embeddings(void *params_ptr, void *state_pr, int *tokens, int tokenSize, float *res_embeddings)
+int get_token_embeddings(void *params_ptr, void *state_pr, const int *tokens, int tokenSize, float *res_embeddings)
 {
     gpt_params *params_p = (gpt_params *)params_ptr;
     llama_context *ctx = (llama_context *)state_pr;
@@ -118,7 +232,7 @@ int get_token_embeddings(void *params_ptr, void *state_pr, int *tokens, int toke
     return get_embeddings(params_ptr, state_pr, res_embeddings);
 }
 
-int eval(void *params_ptr, void *state_pr, char *text)
+int eval(void *params_ptr, void *state_pr, const char *text)
 {
     gpt_params *params_p = (gpt_params *)params_ptr;
     llama_context *ctx = (llama_context *)state_pr;
@@ -131,7 +245,7 @@ int eval(void *params_ptr, void *state_pr, char *text)
 
     if (n_prompt_tokens < 1)
     {
-        fprintf(stderr, "%s : failed to tokenize prompt\n", __func__);
+        binding_log(LLAMA_BINDING_LOG_ERROR, "%s: failed to tokenize prompt\n", __func__);
         return 1;
     }
 
@@ -139,10 +253,12 @@ int eval(void *params_ptr, void *state_pr, char *text)
     return llama_eval(ctx, tokens.data(), n_prompt_tokens, n_past);
 }
 
-int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
+int llama_predict(void *params_ptr, void *state_pr, char **result, int *stop_reason, llama_binding_predict_stats *stats)
 {
     gpt_params *params_p = (gpt_params *)params_ptr;
     llama_context *ctx = (llama_context *)state_pr;
+
+    memset(stats, 0, sizeof(*stats));
     
     llama_set_n_threads(ctx, params_p->n_threads, params_p->n_threads_batch);
 
@@ -155,21 +271,14 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
 
     std::mt19937 rng(params_p->seed);
 
-    // print input
-    if (debug)
-    {
-        fprintf(stderr, "%s: input: %s\n", __func__, params_p->prompt.c_str());
-    }
+    binding_log(LLAMA_BINDING_LOG_DEBUG, "%s: input: %s\n", __func__, params_p->prompt.c_str());
 
     std::string path_session = params_p->path_prompt_cache;
     std::vector<llama_token> session_tokens;
 
     if (!path_session.empty())
     {
-        if (debug)
-        {
-            fprintf(stderr, "%s: attempting to load saved session from '%s'\n", __func__, path_session.c_str());
-        }
+        binding_log(LLAMA_BINDING_LOG_INFO, "%s: attempting to load saved session from '%s'\n", __func__, path_session.c_str());
         // fopen to check for existing session
         FILE *fp = std::fopen(path_session.c_str(), "rb");
         if (fp != NULL)
@@ -180,22 +289,16 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
             size_t n_token_count_out = 0;
             if (!llama_load_session_file(ctx, path_session.c_str(), session_tokens.data(), session_tokens.capacity(), &n_token_count_out))
             {
-                fprintf(stderr, "%s: error: failed to load session file '%s'\n", __func__, path_session.c_str());
+                binding_log(LLAMA_BINDING_LOG_ERROR, "%s: failed to load session file '%s'\n", __func__, path_session.c_str());
                 return 1;
             }
             session_tokens.resize(n_token_count_out);
             llama_set_rng_seed(ctx, params_p->seed);
-            if (debug)
-            {
-                fprintf(stderr, "%s: loaded a session with prompt size of %d tokens\n", __func__, (int)session_tokens.size());
-            }
+            binding_log(LLAMA_BINDING_LOG_INFO, "%s: loaded a session with prompt size of %d tokens\n", __func__, (int)session_tokens.size());
         }
         else
         {
-            if (debug)
-            {
-                fprintf(stderr, "%s: session file does not exist, will create\n", __func__);
-            }
+            binding_log(LLAMA_BINDING_LOG_INFO, "%s: session file does not exist, will create\n", __func__);
         }
     }
 
@@ -211,6 +314,7 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
     {
         embd_inp = session_tokens;
     }
+    stats->n_prompt_tokens = (int)embd_inp.size();
 
     // debug message about similarity of saved session, if applicable
     size_t n_matching_session_tokens = 0;
@@ -224,28 +328,27 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
             }
             n_matching_session_tokens++;
         }
-        if (debug)
+        if (params_p->prompt.empty() && n_matching_session_tokens == embd_inp.size())
         {
-            if (params_p->prompt.empty() && n_matching_session_tokens == embd_inp.size())
-            {
-                fprintf(stderr, "%s: using full prompt from session file\n", __func__);
-            }
-            else if (n_matching_session_tokens >= embd_inp.size())
-            {
-                fprintf(stderr, "%s: session file has exact match for prompt!\n", __func__);
-            }
-            else if (n_matching_session_tokens < (embd_inp.size() / 2))
-            {
-                fprintf(stderr, "%s: warning: session file has low similarity to prompt (%zu / %zu tokens); will mostly be reevaluated\n",
+            binding_log(LLAMA_BINDING_LOG_INFO, "%s: using full prompt from session file\n", __func__);
+        }
+        else if (n_matching_session_tokens >= embd_inp.size())
+        {
+            binding_log(LLAMA_BINDING_LOG_INFO, "%s: session file has exact match for prompt!\n", __func__);
+        }
+        else if (n_matching_session_tokens < (embd_inp.size() / 2))
+        {
+            binding_log(LLAMA_BINDING_LOG_WARN, "%s: session file has low similarity to prompt (%zu / %zu tokens); will mostly be reevaluated\n",
                         __func__, n_matching_session_tokens, embd_inp.size());
-            }
-            else
-            {
-                fprintf(stderr, "%s: session file matches %zu / %zu tokens of prompt\n",
+        }
+        else
+        {
+            binding_log(LLAMA_BINDING_LOG_INFO, "%s: session file matches %zu / %zu tokens of prompt\n",
                         __func__, n_matching_session_tokens, embd_inp.size());
-            }
         }
     }
+    stats->n_session_tokens = (int)n_matching_session_tokens;
+
     // if we will use the cache for the full prompt without reaching the end of the cache, force
     // reevaluation of the last token token to recalculate the cached logits
     if (!embd_inp.empty() && n_matching_session_tokens == embd_inp.size() &&
@@ -275,6 +378,10 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
     std::vector<llama_token> embd;
     std::string res = "";
 
+    float mirostat_mu = 2.0f * params_p->sparams.mirostat_tau;
+
+    *stop_reason = LLAMA_BINDING_STOP_LENGTH;
+
     // do one empty run to warm up the model
     {
         llama_token tmp[1] = {
@@ -296,6 +403,7 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
             if (n_past + (int)embd.size() > n_ctx)
             {
                 const int n_left = n_past - params_p->n_keep;
+                stats->n_context_swaps++;
 
                 // always keep the first token - BOS
                 n_past = std::max(1, params_p->n_keep);
@@ -353,7 +461,7 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
                 }
                 if (llama_eval(ctx, &embd[i], n_eval, n_past))
                 {
-                    fprintf(stderr, "%s : failed to eval\n", __func__);
+                    binding_log(LLAMA_BINDING_LOG_ERROR, "%s: failed to eval\n", __func__);
                     return 1;
                 }
                 n_past += n_eval;
@@ -371,19 +479,6 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
         if ((int)embd_inp.size() <= n_consumed)
         {
             // out of user input, sample next token
-            const float temp = params_p->sparams.temp;
-            const int32_t top_k = params_p->sparams.top_k <= 0 ? llama_n_vocab(llama_get_model(ctx)) : params_p->sparams.top_k;
-            const float top_p = params_p->sparams.top_p;
-            const float tfs_z = params_p->sparams.tfs_z;
-            const float typical_p = params_p->sparams.typical_p;
-            const int32_t repeat_last_n = params_p->sparams.penalty_last_n < 0 ? n_ctx : params_p->sparams.penalty_last_n;
-            const float repeat_penalty = params_p->sparams.penalty_repeat;
-            const float alpha_presence = params_p->sparams.penalty_present;
-            const float alpha_frequency = params_p->sparams.penalty_freq;
-            const int mirostat = params_p->sparams.mirostat;
-            const float mirostat_tau = params_p->sparams.mirostat_tau;
-            const float mirostat_eta = params_p->sparams.mirostat_eta;
-            const bool penalize_nl = params_p->sparams.penalize_nl;
 
             // optionally save the session on first sample (for faster prompt loading next time)
             if (!path_session.empty() && need_to_save_session && !params_p->prompt_cache_ro)
@@ -392,86 +487,24 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
                 llama_save_session_file(ctx, path_session.c_str(), session_tokens.data(), session_tokens.size());
             }
 
-            llama_token id = 0;
-
-            {
-                auto logits = llama_get_logits(ctx);
-                auto n_vocab = llama_n_vocab(llama_get_model(ctx));
-
-                // Apply params_p->logit_bias map
-                for (auto it = params_p->sparams.logit_bias.begin(); it != params_p->sparams.logit_bias.end(); it++)
-                {
-                    logits[it->first] += it->second;
-                }
-
-                std::vector<llama_token_data> candidates;
-                candidates.reserve(n_vocab);
-                for (llama_token token_id = 0; token_id < n_vocab; token_id++)
-                {
-                    candidates.emplace_back(llama_token_data{token_id, logits[token_id], 0.0f});
-                }
-
-                llama_token_data_array candidates_p = {candidates.data(), candidates.size(), false};
-
-                // Apply penalties
-                float nl_logit = logits[llama_token_nl(llama_get_model(ctx))];
-                auto last_n_repeat = std::min(std::min((int)last_n_tokens.size(), repeat_last_n), n_ctx);
-                llama_sample_repetition_penalties(ctx, &candidates_p,
-                                                last_n_tokens.data() + last_n_tokens.size() - last_n_repeat,
-                                                last_n_repeat, repeat_penalty, alpha_frequency, alpha_presence);
-                if (!penalize_nl)
-                {
-                    logits[llama_token_nl(llama_get_model(ctx))] = nl_logit;
-                }
-
-                if (temp <= 0)
-                {
-                    // Greedy sampling
-                    id = llama_sample_token_greedy(ctx, &candidates_p);
-                }
-                else
-                {
-                    if (mirostat == 1)
-                    {
-                        static float mirostat_mu = 2.0f * mirostat_tau;
-                        const int mirostat_m = 100;
-                        llama_sample_temperature(ctx, &candidates_p, temp);
-                        id = llama_sample_token_mirostat(ctx, &candidates_p, mirostat_tau, mirostat_eta, mirostat_m, &mirostat_mu);
-                    }
-                    else if (mirostat == 2)
-                    {
-                        static float mirostat_mu = 2.0f * mirostat_tau;
-                        llama_sample_temperature(ctx, &candidates_p, temp);
-                        id = llama_sample_token_mirostat_v2(ctx, &candidates_p, mirostat_tau, mirostat_eta, &mirostat_mu);
-                    }
-                    else
-                    {
-                        // Temperature sampling
-                        llama_sample_top_k(ctx, &candidates_p, top_k, 1);
-                        llama_sample_tail_free(ctx, &candidates_p, tfs_z, 1);
-                        llama_sample_typical(ctx, &candidates_p, typical_p, 1);
-                        llama_sample_top_p(ctx, &candidates_p, top_p, 1);
-                        llama_sample_temperature(ctx, &candidates_p, temp);
-                        id = llama_sample_token(ctx, &candidates_p);
-                    }
-                }
-                // printf("`%d`", candidates_p.size);
+            const llama_token id = sample_token(ctx, params_p, llama_get_logits(ctx), last_n_tokens.data(), last_n_tokens.size(), &mirostat_mu);
 
-                last_n_tokens.erase(last_n_tokens.begin());
-                last_n_tokens.push_back(id);
-            }
+            last_n_tokens.erase(last_n_tokens.begin());
+            last_n_tokens.push_back(id);
 
             // add it to the context
             embd.push_back(id);
 
             // decrement remaining sampling budget
             --n_remain;
+            stats->n_generated++;
 
             // call the token callback, no need to check if one is actually registered, that will
             // be handled on the Go side.
             auto token_str = llama_token_to_str(ctx, id);
             if (!tokenCallback(state_pr, (char*)token_str.c_str()))
             {
+                *stop_reason = LLAMA_BINDING_STOP_CALLBACK;
                 break;
             }
         }
@@ -515,6 +548,7 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
 
                 if (last_output.find(antiprompt.c_str(), search_start_pos) != std::string::npos)
                 {
+                    *stop_reason = LLAMA_BINDING_STOP_PROMPT;
                     goto end;
                 }
             }
@@ -523,29 +557,19 @@ int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug)
         // end of text token
         if (!embd.empty() && embd.back() == llama_token_eos(llama_get_model(ctx)))
         {
+            *stop_reason = LLAMA_BINDING_STOP_EOS;
             break;
         }
     }
 
     if (!path_session.empty() && params_p->prompt_cache_all && !params_p->prompt_cache_ro)
     {
-        if (debug)
-        {
-            fprintf(stderr, "\n%s: saving final output to session file '%s'\n", __func__, path_session.c_str());
-        }
+        binding_log(LLAMA_BINDING_LOG_INFO, "%s: saving final output to session file '%s'\n", __func__, path_session.c_str());
         llama_save_session_file(ctx, path_session.c_str(), session_tokens.data(), session_tokens.size());
     }
 
 end:
-#if defined(_WIN32)
-    signal(SIGINT, SIG_DFL);
-#endif
-
-    if (debug)
-    {
-        llama_print_timings(ctx);
-        llama_reset_timings(ctx);
-    }
+    stats->n_past = n_past;
 
     *result = strdup(res.c_str());
     return 0;
@@ -554,157 +578,391 @@ end:
 void llama_binding_free_model(void *state_ptr)
 {
     llama_context *ctx = (llama_context *)state_ptr;
+    // every context gets its own model in load_model
+    const llama_model *model = llama_get_model(ctx);
     llama_free(ctx);
+    llama_free_model((llama_model *)model);
+}
+
+void llama_binding_free_result(char *result)
+{
+    free(result);
+}
+
+// params allocated and not yet freed by the calling thread, so tests can check they are freed
+// without being affected by other threads
+static thread_local int live_params = 0;
+
+int llama_binding_live_params(void)
+{
+    return live_params;
 }
 
 void llama_free_params(void *params_ptr)
 {
     gpt_params *params = (gpt_params *)params_ptr;
     delete params;
+    live_params--;
 }
 
-std::vector<std::string> create_vector(const char **strings, int count)
+int llama_binding_tokenize(void *state_pr, const char *text, int *tokens, int n_max_tokens, bool add_bos)
 {
-    std::vector<std::string> *vec = new std::vector<std::string>;
-    for (int i = 0; i < count; i++)
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_tokenize(llama_get_model(ctx), text, strlen(text), tokens, n_max_tokens, add_bos, false);
+}
+
+int llama_binding_token_to_piece(void *state_pr, int token, char *buf, int length)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_token_to_piece(llama_get_model(ctx), token, buf, length);
+}
+
+int llama_binding_sample(void *params_ptr, void *state_pr, int idx, const int *last_tokens, int n_last, float *mirostat_mu, unsigned int seed)
+{
+    gpt_params *params_p = (gpt_params *)params_ptr;
+    llama_context *ctx = (llama_context *)state_pr;
+
+    llama_set_rng_seed(ctx, seed);
+
+    return sample_token(ctx, params_p, llama_get_logits_ith(ctx, idx), last_tokens, n_last, mirostat_mu);
+}
+
+int llama_binding_token_eos(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_token_eos(llama_get_model(ctx));
+}
+
+int llama_binding_token_bos(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_token_bos(llama_get_model(ctx));
+}
+
+int llama_binding_token_nl(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_token_nl(llama_get_model(ctx));
+}
+
+int llama_binding_max_devices(void)
+{
+    return LLAMA_MAX_DEVICES;
+}
+
+int llama_binding_n_embd(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_n_embd(llama_get_model(ctx));
+}
+
+int llama_binding_n_ctx_train(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_n_ctx_train(llama_get_model(ctx));
+}
+
+float llama_binding_rope_freq_scale_train(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_rope_freq_scale_train(llama_get_model(ctx));
+}
+
+int llama_binding_vocab_type(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_vocab_type(llama_get_model(ctx));
+}
+
+unsigned long long llama_binding_model_size(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_model_size(llama_get_model(ctx));
+}
+
+unsigned long long llama_binding_model_n_params(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_model_n_params(llama_get_model(ctx));
+}
+
+int llama_binding_model_desc(void *state_pr, char *buf, int size)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_model_desc(llama_get_model(ctx), buf, size);
+}
+
+int llama_binding_model_meta(void *state_pr, const char *key, char *buf, int size)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_model_meta_val_str(llama_get_model(ctx), key, buf, size);
+}
+
+int llama_binding_n_vocab(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_n_vocab(llama_get_model(ctx));
+}
+
+int llama_binding_n_ctx(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_n_ctx(ctx);
+}
+
+void llama_binding_set_n_threads(void *state_pr, int n_threads)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    llama_set_n_threads(ctx, n_threads, n_threads);
+}
+
+llama_binding_timings llama_binding_get_timings(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    const llama_timings timings = llama_get_timings(ctx);
+
+    llama_binding_timings res;
+    res.t_load_ms = timings.t_load_ms;
+    res.t_sample_ms = timings.t_sample_ms;
+    res.n_sample = timings.n_sample;
+    res.t_prompt_eval_ms = timings.t_p_eval_ms;
+    res.n_prompt_eval = timings.n_p_eval;
+    res.t_eval_ms = timings.t_eval_ms;
+    res.n_eval = timings.n_eval;
+
+    return res;
+}
+
+void llama_binding_reset_timings(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    llama_reset_timings(ctx);
+}
+
+int llama_binding_decode(void *state_pr, int n_tokens, const int *tokens, const int *pos, const int *n_seq_id, const int *seq_ids, const bool *logits)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    int n_seq_max = 1;
+    for (int i = 0; i < n_tokens; i++)
+    {
+        n_seq_max = std::max(n_seq_max, n_seq_id[i]);
+    }
+
+    llama_batch batch = llama_batch_init(n_tokens, 0, n_seq_max);
+
+    // seq_ids is flattened, n_seq_id[i] entries belong to token i
+    int k = 0;
+    for (int i = 0; i < n_tokens; i++)
     {
-        vec->push_back(std::string(strings[i]));
+        batch.token[i] = tokens[i];
+        batch.pos[i] = pos[i];
+        batch.n_seq_id[i] = n_seq_id[i];
+        for (int j = 0; j < n_seq_id[i]; j++)
+        {
+            batch.seq_id[i][j] = seq_ids[k++];
+        }
+        batch.logits[i] = logits[i];
     }
-    return *vec;
+    batch.n_tokens = n_tokens;
+
+    const int ret = llama_decode(ctx, batch);
+
+    llama_batch_free(batch);
+
+    return ret;
 }
 
-void delete_vector(std::vector<std::string> *vec)
+float *llama_binding_get_logits(void *state_pr, int i)
 {
-    delete vec;
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_get_logits_ith(ctx, i);
+}
+
+void llama_binding_kv_cache_clear(void *state_pr)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    llama_kv_cache_clear(ctx);
 }
 
-int load_state(void *ctx, char *statefile, char *modes)
+void llama_binding_kv_cache_seq_rm(void *state_pr, int seq_id, int p0, int p1)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    llama_kv_cache_seq_rm(ctx, seq_id, p0, p1);
+}
+
+void llama_binding_kv_cache_seq_cp(void *state_pr, int seq_id_src, int seq_id_dst, int p0, int p1)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    llama_kv_cache_seq_cp(ctx, seq_id_src, seq_id_dst, p0, p1);
+}
+
+void llama_binding_kv_cache_seq_keep(void *state_pr, int seq_id)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    llama_kv_cache_seq_keep(ctx, seq_id);
+}
+
+int llama_binding_quantize(const char *fname_inp, const char *fname_out, int ftype, int n_threads, bool allow_requantize, bool quantize_output_tensor, bool only_copy, void *progress)
+{
+    auto params = llama_model_quantize_default_params();
+
+    params.ftype = (enum llama_ftype)ftype;
+    params.nthread = n_threads;
+    params.allow_requantize = allow_requantize;
+    params.quantize_output_tensor = quantize_output_tensor;
+    params.only_copy = only_copy;
+
+    install_log_callback();
+    llama_backend_init(false);
+
+    quantize_progress = progress;
+    int res = llama_model_quantize(fname_inp, fname_out, &params);
+    quantize_progress = nullptr;
+
+    return res;
+}
+
+int llama_binding_apply_lora(void *state_pr, const char *path_lora, float scale, const char *path_base_model, int n_threads)
+{
+    llama_context *ctx = (llama_context *)state_pr;
+
+    return llama_model_apply_lora_from_file(llama_get_model(ctx), path_lora, scale,
+                                            path_base_model[0] != '\0' ? path_base_model : nullptr, n_threads);
+}
+
+std::vector<std::string> create_vector(const char *const *strings, int count)
+{
+    std::vector<std::string> vec;
+    for (int i = 0; i < count; i++)
+    {
+        vec.push_back(std::string(strings[i]));
+    }
+    return vec;
+}
+
+int load_state(void *ctx, const char *statefile, const char *modes)
 {
     llama_context *state = (llama_context *)ctx;
-    const llama_context *constState = static_cast<const llama_context *>(state);
     const size_t state_size = llama_get_state_size(state);
-    uint8_t *state_mem = new uint8_t[state_size];
 
+    FILE *fp_read = fopen(statefile, modes);
+    if (fp_read == nullptr)
     {
-        FILE *fp_read = fopen(statefile, modes);
-        if (state_size != llama_get_state_size(constState))
-        {
-            fprintf(stderr, "\n%s : failed to validate state size\n", __func__);
-            return 1;
-        }
+        binding_log(LLAMA_BINDING_LOG_ERROR, "%s: failed to open %s\n", __func__, statefile);
+        return 1;
+    }
 
-        const size_t ret = fread(state_mem, 1, state_size, fp_read);
-        if (ret != state_size)
-        {
-            fprintf(stderr, "\n%s : failed to read state\n", __func__);
-            return 1;
-        }
+    std::vector<uint8_t> state_mem(state_size);
+
+    const size_t ret = fread(state_mem.data(), 1, state_size, fp_read);
+    fclose(fp_read);
 
-        llama_set_state_data(state, state_mem); // could also read directly from memory mapped file
-        fclose(fp_read);
+    if (ret != state_size)
+    {
+        binding_log(LLAMA_BINDING_LOG_ERROR, "%s: failed to read state\n", __func__);
+        return 1;
     }
 
+    llama_set_state_data(state, state_mem.data()); // could also read directly from memory mapped file
+
     return 0;
 }
 
-void save_state(void *ctx, char *dst, char *modes)
+int save_state(void *ctx, const char *dst, const char *modes)
 {
     llama_context *state = (llama_context *)ctx;
 
     const size_t state_size = llama_get_state_size(state);
-    uint8_t *state_mem = new uint8_t[state_size];
+    std::vector<uint8_t> state_mem(state_size);
 
     // Save state (rng, logits, embedding and kv_cache) to file
+    FILE *fp_write = fopen(dst, modes);
+    if (fp_write == nullptr)
     {
-        FILE *fp_write = fopen(dst, modes);
-        llama_copy_state_data(state, state_mem); // could also copy directly to memory mapped file
-        fwrite(state_mem, 1, state_size, fp_write);
-        fclose(fp_write);
+        binding_log(LLAMA_BINDING_LOG_ERROR, "%s: failed to open %s\n", __func__, dst);
+        return 1;
     }
+
+    llama_copy_state_data(state, state_mem.data()); // could also copy directly to memory mapped file
+    const size_t ret = fwrite(state_mem.data(), 1, state_size, fp_write);
+    fclose(fp_write);
+
+    return ret == state_size ? 0 : 1;
 }
 
-void *llama_allocate_params(const char *prompt, int seed, int threads, int tokens, int top_k,
-                            float top_p, float temp, float repeat_penalty, int repeat_last_n, bool ignore_eos, bool memory_f16, int n_batch, int n_keep, const char **antiprompt, int antiprompt_count,
-                            float tfs_z, float typical_p, float frequency_penalty, float presence_penalty, int mirostat, float mirostat_eta, float mirostat_tau, bool penalize_nl, const char *logit_bias, const char *session_file, bool prompt_cache_all, bool mlock, bool mmap,
-                            const char *maingpu, const char *tensorsplit, bool prompt_cache_ro)
+void *llama_allocate_params(const llama_binding_params *binding_params)
 {
-    gpt_params *params = new gpt_params;
-    params->seed = seed;
-    params->n_threads = threads;
-    params->n_threads_batch = threads;
-    params->n_predict = tokens;
-    params->sparams.penalty_last_n = repeat_last_n;
-    params->prompt_cache_ro = prompt_cache_ro;
-    params->sparams.top_k = top_k;
-    params->sparams.top_p = top_p;
-//    params->memory_f16 = memory_f16;
-    params->sparams.temp = temp;
-    params->use_mmap = mmap;
-    params->use_mlock = mlock;
-    params->sparams.penalty_repeat = repeat_penalty;
-    params->n_batch = n_batch;
-    params->n_keep = n_keep;
-    if (maingpu[0] != '\0')
-    {
-        params->main_gpu = std::stoi(maingpu);
-    }
-
-    if (tensorsplit[0] != '\0')
-    {
-        std::string arg_next = tensorsplit;
-        // split string by , and /
-        const std::regex regex{R"([,/]+)"};
-        std::sregex_token_iterator it{arg_next.begin(), arg_next.end(), regex, -1};
-        std::vector<std::string> split_arg{it, {}};
-        GGML_ASSERT(split_arg.size() <= LLAMA_MAX_DEVICES);
-
-        for (size_t i = 0; i < LLAMA_MAX_DEVICES; ++i)
-        {
-            if (i < split_arg.size())
-            {
-                params->tensor_split[i] = std::stof(split_arg[i]);
-            }
-            else
-            {
-                params->tensor_split[i] = 0.0f;
-            }
-        }
-    }
+    const llama_binding_params &p = *binding_params;
 
-    params->prompt_cache_all = prompt_cache_all;
-    params->path_prompt_cache = session_file;
-
-    /* if (ignore_eos) // TODO: Cannot be set before context is allocated (llama_token_eos requires context access)
-    {
-        params->logit_bias[llama_token_eos()] = -INFINITY;
-	}
-    */
-    if (antiprompt_count > 0)
+    gpt_params *params = new gpt_params;
+    live_params++;
+    params->seed = p.seed;
+    params->n_threads = p.threads;
+    params->n_threads_batch = p.threads;
+    params->n_predict = p.n_predict;
+    params->sparams.penalty_last_n = p.repeat_last_n;
+    params->prompt_cache_ro = p.prompt_cache_ro;
+    params->sparams.top_k = p.top_k;
+    params->sparams.top_p = p.top_p;
+//    params->memory_f16 = p.memory_f16;
+    params->sparams.temp = p.temp;
+    params->use_mmap = p.mmap;
+    params->use_mlock = p.mlock;
+    params->sparams.penalty_repeat = p.repeat_penalty;
+    params->n_batch = p.n_batch;
+    params->n_keep = p.n_keep;
+    params->ignore_eos = p.ignore_eos;
+
+    params->prompt_cache_all = p.prompt_cache_all;
+    params->path_prompt_cache = p.session_file;
+
+    if (p.antiprompt_count > 0)
     {
-        params->antiprompt = create_vector(antiprompt, antiprompt_count);
+        params->antiprompt = create_vector(p.antiprompt, p.antiprompt_count);
     }
-    params->sparams.tfs_z = tfs_z;
-    params->sparams.typical_p = typical_p;
-    params->sparams.penalty_present = presence_penalty;
-    params->sparams.mirostat = mirostat;
-    params->sparams.mirostat_eta = mirostat_eta;
-    params->sparams.mirostat_tau = mirostat_tau;
-    params->sparams.penalize_nl = penalize_nl;
-    std::stringstream ss(logit_bias);
-    llama_token key;
-    char sign;
-    std::string value_str;
-    if (ss >> key && ss >> sign && std::getline(ss, value_str) && (sign == '+' || sign == '-'))
+    params->sparams.tfs_z = p.tfs_z;
+    params->sparams.typical_p = p.typical_p;
+    params->sparams.penalty_present = p.presence_penalty;
+    params->sparams.mirostat = p.mirostat;
+    params->sparams.mirostat_eta = p.mirostat_eta;
+    params->sparams.mirostat_tau = p.mirostat_tau;
+    params->sparams.penalize_nl = p.penalize_nl;
+    // parsed and checked against the vocabulary on the rust side
+    if (p.logit_bias_token >= 0)
     {
-        params->sparams.logit_bias[key] = std::stof(value_str) * ((sign == '-') ? -1.0f : 1.0f);
+        params->sparams.logit_bias[p.logit_bias_token] = p.logit_bias;
     }
-    params->sparams.penalty_freq = frequency_penalty;
-    params->prompt = prompt;
+    params->sparams.penalty_freq = p.frequency_penalty;
+    params->prompt = p.prompt;
 
     return params;
 }
 
-void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool mlock, bool embeddings, bool mmap, bool low_vram, bool vocab_only, int n_gpu_layers, int n_batch, const char *maingpu, const char *tensorsplit, bool numa)
+void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool mlock, bool embeddings, bool mmap, bool low_vram, bool vocab_only, int n_gpu_layers, int n_batch, int main_gpu, const float *tensor_split, int n_tensor_split, bool numa, void *progress)
 {
     // load the model
     auto lparams = llama_context_default_params();
@@ -720,50 +978,52 @@ void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool
     // mparams.low_vram = low_vram; LOW_VRAM not a thing anymore in the API? verify
     mparams.vocab_only = vocab_only;
 
-    if (maingpu[0] != '\0')
-    {
-        mparams.main_gpu = std::stoi(maingpu);
-    }
+    mparams.main_gpu = main_gpu;
 
-    if (tensorsplit[0] != '\0')
+    // only read while loading, the splits are validated on the Rust side
+    float tsplit[LLAMA_MAX_DEVICES] = {0};
+    if (n_tensor_split > 0)
     {
-        std::string arg_next = tensorsplit;
-        // split string by , and /
-        const std::regex regex{R"([,/]+)"};
-        std::sregex_token_iterator it{arg_next.begin(), arg_next.end(), regex, -1};
-        std::vector<std::string> split_arg{it, {}};
-        GGML_ASSERT(split_arg.size() <= LLAMA_MAX_DEVICES);
-
-	float *tsplit = (float*)malloc(sizeof(float) * LLAMA_MAX_DEVICES);
-
-        for (size_t i = 0; i < LLAMA_MAX_DEVICES; ++i)
+        for (int i = 0; i < n_tensor_split && i < LLAMA_MAX_DEVICES; ++i)
         {
-            if (i < split_arg.size())
-            {
-                tsplit[i] = std::stof(split_arg[i]);
-            }
-            else
-            {
-                tsplit[i] = 0.0f;
-            }
+            tsplit[i] = tensor_split[i];
         }
-	mparams.tensor_split = tsplit;
+        mparams.tensor_split = tsplit;
     }
 
     if (n_batch > 0)
         lparams.n_batch = n_batch;
 
+    if (progress != nullptr)
+    {
+        mparams.progress_callback = loadProgressCallback;
+        mparams.progress_callback_user_data = progress;
+    }
+
+    install_log_callback();
     llama_backend_init(numa);
     void *res = nullptr;
+    llama_model *model = nullptr;
     try
     {
-        auto model = llama_load_model_from_file(fname, mparams);
-	res = llama_new_context_with_model(model, lparams);
+        model = llama_load_model_from_file(fname, mparams);
+        // failed or cancelled by the progress callback
+        if (model == nullptr)
+        {
+            return res;
+        }
+        res = llama_new_context_with_model(model, lparams);
     }
     catch (std::runtime_error &e)
     {
-        fprintf(stderr, "failed %s", e.what());
-        return res;
+        binding_log(LLAMA_BINDING_LOG_ERROR, "%s: failed to load model: %s\n", __func__, e.what());
+        res = nullptr;
+    }
+
+    // without a context nothing frees the model later, see llama_binding_free_model
+    if (res == nullptr && model != nullptr)
+    {
+        llama_free_model(model);
     }
 
     return res;
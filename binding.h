@@ -7,35 +7,167 @@ extern "C"
 
 #include <stdbool.h>
 
+#define LLAMA_BINDING_STOP_LENGTH 0
+#define LLAMA_BINDING_STOP_EOS 1
+#define LLAMA_BINDING_STOP_PROMPT 2
+#define LLAMA_BINDING_STOP_CALLBACK 3
+
+// ggml_log_level values, debug is only used by the binding itself
+#define LLAMA_BINDING_LOG_ERROR 2
+#define LLAMA_BINDING_LOG_WARN 3
+#define LLAMA_BINDING_LOG_INFO 4
+#define LLAMA_BINDING_LOG_DEBUG 5
+
+    // PredictOptions as passed to llama_allocate_params, every string is copied
+    typedef struct llama_binding_params
+    {
+        const char *prompt;
+        int seed;
+        int threads;
+        int n_predict;
+        int top_k;
+        float top_p;
+        float temp;
+        float repeat_penalty;
+        int repeat_last_n;
+        bool ignore_eos;
+        bool memory_f16;
+        int n_batch;
+        int n_keep;
+        const char *const *antiprompt;
+        int antiprompt_count;
+        float tfs_z;
+        float typical_p;
+        float frequency_penalty;
+        float presence_penalty;
+        int mirostat;
+        float mirostat_eta;
+        float mirostat_tau;
+        bool penalize_nl;
+        // -1 when there is no logit bias
+        int logit_bias_token;
+        float logit_bias;
+        const char *session_file;
+        bool prompt_cache_all;
+        bool mlock;
+        bool mmap;
+        bool prompt_cache_ro;
+    } llama_binding_params;
+
+    // what a llama_predict call did, for metrics
+    typedef struct llama_binding_predict_stats
+    {
+        int n_prompt_tokens;
+        int n_generated;
+        int n_context_swaps;
+        // prompt tokens reused from the session file
+        int n_session_tokens;
+        // KV cache cells in use at the end
+        int n_past;
+    } llama_binding_predict_stats;
+
+    // llama_timings without the start and end timestamps
+    typedef struct llama_binding_timings
+    {
+        double t_load_ms;
+        double t_sample_ms;
+        int n_sample;
+        double t_prompt_eval_ms;
+        int n_prompt_eval;
+        double t_eval_ms;
+        int n_eval;
+    } llama_binding_timings;
+
     extern unsigned char tokenCallback(void *, char *);
 
-    int load_state(void *ctx, char *statefile, char *modes);
+    extern void quantizeProgressCallback(void *, int, int);
 
-    int eval(void *params_ptr, void *ctx, char *text);
+    extern bool loadProgressCallback(float, void *);
 
-    void save_state(void *ctx, char *dst, char *modes);
+    extern void logCallback(int, const char *, const char *);
 
-    void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool mlock, bool embeddings, bool mmap, bool low_vram, bool vocab_only, int n_gpu, int n_batch, const char *maingpu, const char *tensorsplit, bool numa);
+    int load_state(void *ctx, const char *statefile, const char *modes);
+
+    int eval(void *params_ptr, void *ctx, const char *text);
+
+    int save_state(void *ctx, const char *dst, const char *modes);
+
+    void *load_model(const char *fname, int n_ctx, int n_seed, bool memory_f16, bool mlock, bool embeddings, bool mmap, bool low_vram, bool vocab_only, int n_gpu, int n_batch, int main_gpu, const float *tensor_split, int n_tensor_split, bool numa, void *progress);
 
     int get_embeddings(void *params_ptr, void *state_pr, float *res_embeddings);
 
-    int get_token_embeddings(void *params_ptr, void *state_pr, int *tokens, int tokenSize, float *res_embeddings);
+    int get_token_embeddings(void *params_ptr, void *state_pr, const int *tokens, int tokenSize, float *res_embeddings);
 
-    void *llama_allocate_params(const char *prompt, int seed, int threads, int tokens,
-                                int top_k, float top_p, float temp, float repeat_penalty,
-                                int repeat_last_n, bool ignore_eos, bool memory_f16,
-                                int n_batch, int n_keep, const char **antiprompt, int antiprompt_count,
-                                float tfs_z, float typical_p, float frequency_penalty, float presence_penalty, int mirostat, float mirostat_eta, float mirostat_tau, bool penalize_nl, const char *logit_bias, const char *session_file, bool prompt_cache_all, bool mlock, bool mmap, const char *maingpu, const char *tensorsplit, bool prompt_cache_ro);
+    void *llama_allocate_params(const llama_binding_params *binding_params);
 
     void llama_free_params(void *params_ptr);
 
+    int llama_binding_live_params(void);
+
     void llama_binding_free_model(void *state);
 
-    int llama_predict(void *params_ptr, void *state_pr, char **result, bool debug);
+    int llama_predict(void *params_ptr, void *state_pr, char **result, int *stop_reason, llama_binding_predict_stats *stats);
+
+    void llama_binding_free_result(char *result);
+
+    int llama_binding_tokenize(void *state_pr, const char *text, int *tokens, int n_max_tokens, bool add_bos);
+
+    int llama_binding_token_to_piece(void *state_pr, int token, char *buf, int length);
+
+    int llama_binding_sample(void *params_ptr, void *state_pr, int idx, const int *last_tokens, int n_last, float *mirostat_mu, unsigned int seed);
+
+    int llama_binding_token_eos(void *state_pr);
+
+    int llama_binding_token_bos(void *state_pr);
+
+    int llama_binding_token_nl(void *state_pr);
+
+    int llama_binding_max_devices(void);
+
+    int llama_binding_n_embd(void *state_pr);
+
+    int llama_binding_n_ctx_train(void *state_pr);
+
+    float llama_binding_rope_freq_scale_train(void *state_pr);
+
+    int llama_binding_vocab_type(void *state_pr);
+
+    unsigned long long llama_binding_model_size(void *state_pr);
+
+    unsigned long long llama_binding_model_n_params(void *state_pr);
+
+    int llama_binding_model_desc(void *state_pr, char *buf, int size);
+
+    int llama_binding_model_meta(void *state_pr, const char *key, char *buf, int size);
+
+    int llama_binding_n_vocab(void *state_pr);
+
+    int llama_binding_n_ctx(void *state_pr);
+
+    void llama_binding_set_n_threads(void *state_pr, int n_threads);
+
+    llama_binding_timings llama_binding_get_timings(void *state_pr);
+
+    void llama_binding_reset_timings(void *state_pr);
+
+    int llama_binding_decode(void *state_pr, int n_tokens, const int *tokens, const int *pos, const int *n_seq_id, const int *seq_ids, const bool *logits);
+
+    float *llama_binding_get_logits(void *state_pr, int i);
+
+    void llama_binding_kv_cache_clear(void *state_pr);
+
+    void llama_binding_kv_cache_seq_rm(void *state_pr, int seq_id, int p0, int p1);
+
+    void llama_binding_kv_cache_seq_cp(void *state_pr, int seq_id_src, int seq_id_dst, int p0, int p1);
+
+    void llama_binding_kv_cache_seq_keep(void *state_pr, int seq_id);
+
+    int llama_binding_quantize(const char *fname_inp, const char *fname_out, int ftype, int n_threads, bool allow_requantize, bool quantize_output_tensor, bool only_copy, void *progress);
+
+    int llama_binding_apply_lora(void *state_pr, const char *path_lora, float scale, const char *path_base_model, int n_threads);
 
 #ifdef __cplusplus
 }
 
-std::vector<std::string> create_vector(const char **strings, int count);
-void delete_vector(std::vector<std::string> *vec);
+std::vector<std::string> create_vector(const char *const *strings, int count);
 #endif
@@ -9,6 +9,10 @@ fn compile_bindings(out_path: &Path) {
     let bindings = bindgen::Builder::default()
         .header("./binding.h")
         .blocklist_function("tokenCallback")
+        .blocklist_function("quantizeProgressCallback")
+        .blocklist_function("loadProgressCallback")
+        .blocklist_function("logCallback")
+        .derive_default(true)
         .parse_callbacks(Box::new(bindgen::CargoCallbacks))
         .generate()
         .expect("Unable to generate bindings");
@@ -12,23 +12,23 @@ fn main() {
     )
     .unwrap();
 
-    let predict_options = PredictOptions {
-        tokens: 0,
-        threads: 14,
-        top_k: 90,
-        top_p: 0.86,
-        token_callback: Some(Box::new(|token| {
-            println!("token1: {}", token);
-
-            true
-        })),
-        ..Default::default()
-    };
+    let predict_options = PredictOptions::builder()
+        .max_tokens(None)
+        .threads(14)
+        .top_k(90)
+        .top_p(0.86)
+        .build()
+        .unwrap();
 
     llama
-        .predict(
+        .predict_with_callback(
             "what are the national animals of india".into(),
-             predict_options,
+            predict_options,
+            Box::new(|token| {
+                println!("token1: {}", token);
+
+                true
+            }),
         )
         .unwrap();
 }
\ No newline at end of file
@@ -1,33 +1,33 @@
 use llama_cpp_rs::{
-    options::{ModelOptions, PredictOptions},
+    options::{GpuLayers, ModelOptions, PredictOptions},
     LLama,
 };
 
 fn main() {
-    let model_options = ModelOptions {
-        n_gpu_layers: 12,
-        ..Default::default()
-    };
+    let model_options = ModelOptions::builder()
+        .gpu_layers(GpuLayers::N(12))
+        .build()
+        .unwrap();
 
     let llama = LLama::new("/models/<your model>.bin".into(), &model_options).unwrap();
 
-    let predict_options = PredictOptions {
-        tokens: 0,
-        threads: 14,
-        top_k: 90,
-        top_p: 0.86,
-        token_callback: Some(Box::new(|token| {
-            println!("token1: {}", token);
-
-            true
-        })),
-        ..Default::default()
-    };
+    let predict_options = PredictOptions::builder()
+        .max_tokens(None)
+        .threads(14)
+        .top_k(90)
+        .top_p(0.86)
+        .build()
+        .unwrap();
 
     llama
-        .predict(
+        .predict_with_callback(
             "what are the national animals of india".into(),
             predict_options,
+            Box::new(|token| {
+                println!("token1: {}", token);
+
+                true
+            }),
         )
         .unwrap();
 }
@@ -0,0 +1,109 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::options::TokenCallback;
+
+// a flag shared between the caller and a running generation, generation stops with
+// StopReason::Callback at the next token once it is set. Pass it as PredictOptions::cancel to
+// cover every generation mode, token callbacks only see single sequence sampling
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    // so the same token can be used for the next generation
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+
+    // a token callback that stops generation once cancelled, for predict_with_callback
+    pub fn callback(&self) -> TokenCallback {
+        self.wrap(|_| true)
+    }
+
+    // wraps a token callback so it also stops generation once cancelled
+    pub fn wrap<F>(&self, callback: F) -> TokenCallback
+    where
+        F: Fn(String) -> bool + Send + 'static,
+    {
+        let token = self.clone();
+
+        Box::new(move |piece| !token.is_cancelled() && callback(piece))
+    }
+
+    // cancels the returned token on Ctrl-C instead of exiting the process, for interactive
+    // tools, the library never installs a signal handler on its own. Only one handler can be set
+    // per process, a second call fails
+    #[cfg(feature = "ctrlc")]
+    pub fn on_ctrl_c() -> Result<Self, Box<dyn std::error::Error>> {
+        let token = Self::new();
+        let handler = token.clone();
+
+        ctrlc::set_handler(move || handler.cancel())?;
+
+        Ok(token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::{generation::StopReason, options::PredictOptions, test_model};
+
+    #[test]
+    #[ignore = "needs a model, set LLAMA_TEST_MODEL"]
+    fn cancellation_only_applies_to_its_call() {
+        let llama = test_model();
+
+        let tokens = Arc::new(AtomicUsize::new(0));
+        let counter = tokens.clone();
+        llama.set_token_callback(Some(Box::new(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            true
+        })));
+
+        let opts = PredictOptions {
+            max_tokens: Some(4),
+            ignore_eos: true,
+            ..Default::default()
+        };
+
+        let cancel = CancelToken::new();
+        cancel.cancel();
+
+        let cancelled = llama
+            .generate(
+                "Hello".to_string(),
+                PredictOptions {
+                    cancel: Some(cancel),
+                    ..opts.clone()
+                },
+            )
+            .unwrap();
+
+        assert_eq!(cancelled.completions[0].stop_reason, StopReason::Callback);
+        assert_eq!(tokens.load(Ordering::SeqCst), 0);
+
+        let generation = llama.generate("Hello".to_string(), opts).unwrap();
+
+        assert_eq!(generation.completions[0].stop_reason, StopReason::Length);
+        assert!(tokens.load(Ordering::SeqCst) > 0);
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    env::{self, VarError},
+    error::Error,
+    fs,
+    path::Path,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+
+use crate::options::{ModelOptions, PredictOptions, Preset};
+
+const MODEL_ENV_PREFIX: &str = "LLAMA_MODEL_";
+const PREDICT_ENV_PREFIX: &str = "LLAMA_PREDICT_";
+
+impl ModelOptions {
+    // values in the file are applied over the defaults, LLAMA_MODEL_* environment variables are
+    // applied over the file, nested fields are separated by a double underscore e.g.
+    // LLAMA_MODEL_GPU__MAIN_GPU=1
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let file = read_file(path.as_ref())?;
+
+        let opts: Self = load(Self::default(), file, MODEL_ENV_PREFIX, &[])?;
+        opts.validate()?;
+
+        Ok(opts)
+    }
+}
+
+impl PredictOptions {
+    // same as ModelOptions::from_file with LLAMA_PREDICT_* variables, a "preset" key in the file
+    // or LLAMA_PREDICT_PRESET picks the preset the file is applied over
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let mut file = read_file(path.as_ref())?;
+
+        let preset_var = format!("{}PRESET", PREDICT_ENV_PREFIX);
+
+        let preset = match env::var(&preset_var) {
+            Ok(preset) => {
+                file.remove("preset");
+                Some(preset)
+            }
+            Err(VarError::NotUnicode(_)) => return Err(not_unicode(&preset_var)),
+            Err(VarError::NotPresent) => match file.remove("preset") {
+                Some(Value::String(preset)) => Some(preset),
+                Some(other) => return Err(format!("Invalid preset {}", other).into()),
+                None => None,
+            },
+        };
+
+        let base = match preset {
+            Some(preset) => Self::preset(preset.parse::<Preset>()?),
+            None => Self::default(),
+        };
+
+        let opts: Self = load(base, file, PREDICT_ENV_PREFIX, &["PRESET"])?;
+        opts.validate()?;
+
+        Ok(opts)
+    }
+}
+
+fn read_file(path: &Path) -> Result<Map<String, Value>, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+
+    let value: Value = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&contents)?,
+        Some("json") => serde_json::from_str(&contents)?,
+        _ => {
+            return Err(format!(
+                "Unsupported options file {}, expected a .toml or .json file",
+                path.display()
+            )
+            .into())
+        }
+    };
+
+    match value {
+        Value::Object(map) => Ok(map),
+        _ => Err(format!("Options file {} is not a table", path.display()).into()),
+    }
+}
+
+fn load<T: Serialize + DeserializeOwned>(
+    base: T,
+    file: Map<String, Value>,
+    prefix: &str,
+    skip: &[&str],
+) -> Result<T, Box<dyn Error>> {
+    let mut value = serde_json::to_value(base)?;
+
+    merge(&mut value, Value::Object(file), "")?;
+    apply_env(&mut value, prefix, skip)?;
+
+    Ok(serde_json::from_value(value)?)
+}
+
+// tables are merged key by key, anything else replaces the base value
+fn merge(base: &mut Value, overlay: Value, path: &str) -> Result<(), Box<dyn Error>> {
+    match (base, overlay) {
+        (Value::Object(base), Value::Object(overlay)) => {
+            for (key, value) in overlay {
+                let path = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", path, key)
+                };
+
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, value, &path)?,
+                    None => return Err(format!("Unknown option {}", path).into()),
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+
+    Ok(())
+}
+
+// other variables can share the prefixes, unknown ones are logged and ignored rather than
+// failing like unknown keys in the file
+fn apply_env(value: &mut Value, prefix: &str, skip: &[&str]) -> Result<(), Box<dyn Error>> {
+    // env::vars panics on anything that isn't UTF-8, names that aren't can't have the prefix
+    for (name, raw) in env::vars_os() {
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+
+        let Some(key) = name.strip_prefix(prefix) else {
+            continue;
+        };
+
+        if skip.contains(&key) {
+            continue;
+        }
+
+        let Ok(raw) = raw.into_string() else {
+            return Err(not_unicode(name));
+        };
+
+        let key = key.to_lowercase();
+        let parts: Vec<&str> = key.split("__").collect();
+
+        if !is_known(value, &parts) {
+            log::warn!(
+                "Ignoring {}, {} is not a known option",
+                name,
+                parts.join(".")
+            );
+            continue;
+        }
+
+        let mut target = &mut *value;
+
+        for part in parts {
+            // an unset optional section, e.g. prompt_lookup, is filled in field by field
+            if target.is_null() {
+                *target = Value::Object(Map::new());
+            }
+
+            let Value::Object(map) = target else {
+                unreachable!("checked by is_known");
+            };
+
+            target = map.entry(part).or_insert(Value::Null);
+        }
+
+        // strings are taken as is, anything else is parsed as JSON so numbers, booleans and lists
+        // can be set, falling back to a string for enum values like "all"
+        *target = match target {
+            Value::String(_) => Value::String(raw),
+            _ => serde_json::from_str(&raw).unwrap_or(Value::String(raw)),
+        };
+    }
+
+    Ok(())
+}
+
+fn not_unicode(name: &str) -> Box<dyn Error> {
+    format!("Environment variable {} is not valid UTF-8", name).into()
+}
+
+// the fields of an unset optional section aren't known here, they are checked when deserializing
+fn is_known(value: &Value, parts: &[&str]) -> bool {
+    let Some((part, rest)) = parts.split_first() else {
+        return true;
+    };
+
+    match value {
+        Value::Null => true,
+        Value::Object(map) => map.get(*part).is_some_and(|value| is_known(value, rest)),
+        _ => false,
+    }
+}
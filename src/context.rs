@@ -0,0 +1,222 @@
+use std::{error::Error, ffi::c_void, marker::PhantomData};
+
+use crate::{
+    llama_binding_decode, llama_binding_get_logits, llama_binding_kv_cache_clear,
+    llama_binding_kv_cache_seq_cp, llama_binding_kv_cache_seq_keep, llama_binding_kv_cache_seq_rm,
+    llama_binding_n_ctx, llama_binding_n_vocab, llama_binding_set_n_threads, LLama,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    tokens: Vec<i32>,
+    positions: Vec<i32>,
+    seq_ids: Vec<Vec<i32>>,
+    logits: Vec<bool>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            tokens: Vec::with_capacity(capacity),
+            positions: Vec::with_capacity(capacity),
+            seq_ids: Vec::with_capacity(capacity),
+            logits: Vec::with_capacity(capacity),
+        }
+    }
+
+    pub fn add(&mut self, token: i32, pos: i32, seq_ids: &[i32], logits: bool) {
+        self.tokens.push(token);
+        self.positions.push(pos);
+        self.seq_ids.push(seq_ids.to_vec());
+        self.logits.push(logits);
+    }
+
+    // adds `tokens` to `seq_id` starting at `start_pos`, optionally requesting logits for the last one
+    pub fn add_sequence(&mut self, tokens: &[i32], start_pos: i32, seq_id: i32, logits_last: bool) {
+        for (i, &token) in tokens.iter().enumerate() {
+            let last = i + 1 == tokens.len();
+            self.add(token, start_pos + i as i32, &[seq_id], logits_last && last);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.tokens.clear();
+        self.positions.clear();
+        self.seq_ids.clear();
+        self.logits.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn tokens(&self) -> &[i32] {
+        &self.tokens
+    }
+
+    pub fn positions(&self) -> &[i32] {
+        &self.positions
+    }
+
+    pub fn seq_ids(&self, i: usize) -> &[i32] {
+        &self.seq_ids[i]
+    }
+
+    pub fn has_logits(&self, i: usize) -> bool {
+        self.logits[i]
+    }
+}
+
+pub struct Context<'a> {
+    state: *mut c_void,
+    n_vocab: usize,
+    n_batch: usize,
+    logits: Vec<bool>,
+    _llama: PhantomData<&'a LLama>,
+}
+
+impl LLama {
+    pub fn context(&mut self) -> Context<'_> {
+        Context::new(self)
+    }
+}
+
+impl<'a> Context<'a> {
+    // the public constructor borrows the model mutably so logits can't be invalidated by other
+    // calls, internal users must not let the context outlive the call that created it
+    pub(crate) fn new(llama: &'a LLama) -> Self {
+        let n_vocab = unsafe { llama_binding_n_vocab(llama.state) } as usize;
+
+        Self {
+            state: llama.state,
+            n_vocab,
+            n_batch: llama.n_batch as usize,
+            logits: Vec::new(),
+            _llama: PhantomData,
+        }
+    }
+
+    pub fn n_vocab(&self) -> usize {
+        self.n_vocab
+    }
+
+    pub fn n_batch(&self) -> usize {
+        self.n_batch
+    }
+
+    pub fn n_ctx(&self) -> usize {
+        unsafe { llama_binding_n_ctx(self.state) as usize }
+    }
+
+    pub fn set_n_threads(&mut self, n_threads: i32) {
+        unsafe { llama_binding_set_n_threads(self.state, n_threads) }
+    }
+
+    pub fn decode(&mut self, batch: &Batch) -> Result<(), Box<dyn Error>> {
+        if batch.is_empty() {
+            return Err("Cannot decode an empty batch".into());
+        }
+
+        // llama_decode asserts on this instead of returning an error
+        if batch.len() > self.n_batch {
+            return Err(format!(
+                "Batch of {} tokens is larger than the batch size {}",
+                batch.len(),
+                self.n_batch
+            )
+            .into());
+        }
+
+        let n_seq_id: Vec<i32> = batch.seq_ids.iter().map(|ids| ids.len() as i32).collect();
+        let seq_ids: Vec<i32> = batch.seq_ids.iter().flatten().copied().collect();
+
+        // logits from the previous batch are invalidated by the call regardless of the outcome
+        self.logits.clear();
+
+        let ret = unsafe {
+            llama_binding_decode(
+                self.state,
+                batch.len() as i32,
+                batch.tokens.as_ptr(),
+                batch.positions.as_ptr(),
+                n_seq_id.as_ptr(),
+                seq_ids.as_ptr(),
+                batch.logits.as_ptr(),
+            )
+        };
+
+        if ret == 1 {
+            return Err("Failed to decode: no KV cache slot available for the batch".into());
+        } else if ret != 0 {
+            return Err("Failed to decode".into());
+        }
+
+        self.logits = batch.logits.clone();
+
+        Ok(())
+    }
+
+    // decodes `tokens` into `seq_id` in chunks of n_batch, returning the batch index holding the
+    // logits of the last token
+    pub(crate) fn decode_tokens(
+        &mut self,
+        tokens: &[i32],
+        start_pos: i32,
+        seq_id: i32,
+    ) -> Result<usize, Box<dyn Error>> {
+        let mut batch = Batch::with_capacity(self.n_batch);
+        let n_chunks = tokens.len().div_ceil(self.n_batch);
+
+        for (n, chunk) in tokens.chunks(self.n_batch).enumerate() {
+            batch.clear();
+            batch.add_sequence(
+                chunk,
+                start_pos + (n * self.n_batch) as i32,
+                seq_id,
+                n + 1 == n_chunks,
+            );
+
+            self.decode(&batch)?;
+        }
+
+        Ok(batch.len().saturating_sub(1))
+    }
+
+    pub fn logits(&self, i: usize) -> &[f32] {
+        assert!(
+            self.logits.get(i).copied().unwrap_or(false),
+            "logits were not requested for batch index {}",
+            i
+        );
+
+        unsafe {
+            let ptr = llama_binding_get_logits(self.state, i as i32);
+            std::slice::from_raw_parts(ptr, self.n_vocab)
+        }
+    }
+
+    pub fn clear_kv_cache(&mut self) {
+        unsafe { llama_binding_kv_cache_clear(self.state) }
+    }
+
+    // p0/p1 < 0 mean the start/end of the sequence respectively
+    pub fn kv_cache_seq_rm(&mut self, seq_id: i32, p0: i32, p1: i32) {
+        unsafe { llama_binding_kv_cache_seq_rm(self.state, seq_id, p0, p1) }
+    }
+
+    pub fn kv_cache_seq_cp(&mut self, seq_id_src: i32, seq_id_dst: i32, p0: i32, p1: i32) {
+        unsafe { llama_binding_kv_cache_seq_cp(self.state, seq_id_src, seq_id_dst, p0, p1) }
+    }
+
+    pub fn kv_cache_seq_keep(&mut self, seq_id: i32) {
+        unsafe { llama_binding_kv_cache_seq_keep(self.state, seq_id) }
+    }
+}
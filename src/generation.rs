@@ -0,0 +1,645 @@
+use std::{
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    context::{Batch, Context},
+    llama_binding_sample,
+    metrics::{self, ErrorKind},
+    options::{GenerationMode, PredictOptions, TokenCallback},
+    params::Params,
+    scoring::log_softmax,
+    speculative::{decode_drafted, lookup_ngram},
+    timings::Timings,
+    LLama, LLAMA_BINDING_STOP_CALLBACK, LLAMA_BINDING_STOP_EOS, LLAMA_BINDING_STOP_PROMPT,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Length,
+    EndOfText,
+    StopPrompt,
+    Callback,
+}
+
+impl StopReason {
+    pub(crate) fn from_binding(reason: i32) -> Self {
+        match reason as u32 {
+            LLAMA_BINDING_STOP_EOS => StopReason::EndOfText,
+            LLAMA_BINDING_STOP_PROMPT => StopReason::StopPrompt,
+            LLAMA_BINDING_STOP_CALLBACK => StopReason::Callback,
+            _ => StopReason::Length,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub text: String,
+    // cumulative log-probability of the generated tokens, when the generation mode tracks it
+    pub score: Option<f32>,
+    pub stop_reason: StopReason,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GenerationStats {
+    pub prompt_tokens: usize,
+    // sampled tokens, for beam search the tokens of the returned completions
+    pub generated_tokens: usize,
+    pub drafted_tokens: usize,
+    pub accepted_tokens: usize,
+}
+
+impl GenerationStats {
+    pub fn acceptance_rate(&self) -> f32 {
+        if self.drafted_tokens == 0 {
+            return 0.0;
+        }
+
+        self.accepted_tokens as f32 / self.drafted_tokens as f32
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Generation {
+    pub completions: Vec<Completion>,
+    pub stats: GenerationStats,
+    pub timings: Timings,
+}
+
+impl LLama {
+    pub fn generate(
+        &self,
+        text: String,
+        opts: PredictOptions,
+    ) -> Result<Generation, Box<dyn Error>> {
+        self.generate_inner(text, opts, None)
+    }
+
+    // the callback only sees tokens when a single sequence is sampled
+    pub fn generate_with_callback(
+        &self,
+        text: String,
+        opts: PredictOptions,
+        callback: TokenCallback,
+    ) -> Result<Generation, Box<dyn Error>> {
+        self.generate_inner(text, opts, Some(callback))
+    }
+
+    pub(crate) fn generate_inner(
+        &self,
+        text: String,
+        opts: PredictOptions,
+        callback: Option<TokenCallback>,
+    ) -> Result<Generation, Box<dyn Error>> {
+        self.validate_predict(&opts)?;
+
+        self.timed(opts.debug_mode, || {
+            self.generate_untimed(text, opts, callback)
+        })
+    }
+
+    // debug_mode logs the timings, like llama_print_timings used to
+    pub(crate) fn timed<F>(&self, debug: bool, generate: F) -> Result<Generation, Box<dyn Error>>
+    where
+        F: FnOnce() -> Result<Generation, Box<dyn Error>>,
+    {
+        self.reset_timings();
+
+        let mut generation = generate().inspect_err(|_| {
+            metrics::record_error(&self.name, ErrorKind::Predict);
+        })?;
+        generation.timings = self.timings();
+
+        metrics::record_generation(&self.name, &generation.stats, &generation.timings);
+
+        if debug {
+            log::info!("{}", generation.timings);
+        }
+
+        Ok(generation)
+    }
+
+    fn generate_untimed(
+        &self,
+        text: String,
+        opts: PredictOptions,
+        callback: Option<TokenCallback>,
+    ) -> Result<Generation, Box<dyn Error>> {
+        if let (GenerationMode::Sampling, 1, Some(lookup)) =
+            (opts.mode, opts.n_completions, opts.prompt_lookup)
+        {
+            return decode_drafted(
+                self,
+                &text,
+                &opts,
+                callback.as_ref(),
+                lookup.n_draft,
+                |history, n| Ok(lookup_ngram(history, &lookup, n)),
+            );
+        }
+
+        match opts.mode {
+            GenerationMode::Sampling if opts.n_completions > 1 => {
+                self.sample_parallel(&text, &opts, opts.n_completions)
+            }
+            GenerationMode::Sampling => self.predict_sampling(text, opts, callback),
+            GenerationMode::BeamSearch {
+                beams,
+                length_penalty,
+                early_stopping,
+            } => self.beam_search(&text, &opts, beams, length_penalty, early_stopping),
+        }
+    }
+
+    // matches the prompt handling of llama_predict, which prepends a space before tokenizing
+    pub(crate) fn tokenize_prompt(&self, text: &str) -> Result<Vec<i32>, Box<dyn Error>> {
+        self.tokenize(&format!(" {}", text), true)
+    }
+
+    // evaluates the prompt once, shares it with `n` sequences through the KV cache and samples
+    // all of them in lockstep, one batch per step
+    fn sample_parallel(
+        &self,
+        text: &str,
+        opts: &PredictOptions,
+        n: usize,
+    ) -> Result<Generation, Box<dyn Error>> {
+        let prompt = self.tokenize_prompt(text)?;
+        let eos = self.token_eos();
+        let params = Params::sampling(opts)?;
+
+        let mut ctx = Context::new(self);
+        ctx.set_n_threads(opts.threads);
+        let n_ctx = ctx.n_ctx() as i32;
+
+        if prompt.len() as i32 >= n_ctx {
+            return Err("Prompt does not fit in the context".into());
+        }
+
+        ctx.clear_kv_cache();
+
+        let last = ctx.decode_tokens(&prompt, 0, 0)?;
+
+        for seq_id in 1..n {
+            ctx.kv_cache_seq_cp(0, seq_id as i32, -1, -1);
+        }
+
+        let base_seed = base_seed(opts.seed);
+
+        let mut sequences: Vec<Sequence> = (0..n)
+            .map(|i| Sequence::new(&prompt, base_seed.wrapping_add(i as u64), opts, last))
+            .collect();
+
+        let mut batch = Batch::with_capacity(n);
+        let mut n_past = prompt.len() as i32;
+
+        loop {
+            batch.clear();
+
+            for (seq_id, seq) in sequences.iter_mut().enumerate() {
+                if seq.stop_reason.is_some() {
+                    continue;
+                }
+
+                if seq.step(self, &ctx, &params, opts, eos).is_none() {
+                    continue;
+                }
+
+                if n_past + 1 >= n_ctx {
+                    seq.stop_reason = Some(StopReason::Length);
+                    continue;
+                }
+
+                seq.logits_idx = batch.len();
+                batch.add(seq.last_token(), n_past, &[seq_id as i32], true);
+            }
+
+            if batch.is_empty() {
+                break;
+            }
+
+            ctx.decode(&batch)?;
+            n_past += 1;
+        }
+
+        let stats = GenerationStats {
+            prompt_tokens: prompt.len(),
+            generated_tokens: sequences.iter().map(Sequence::n_generated).sum(),
+            ..Default::default()
+        };
+
+        Ok(Generation {
+            completions: sequences
+                .into_iter()
+                .map(Sequence::into_completion)
+                .collect(),
+            stats,
+            ..Default::default()
+        })
+    }
+
+    fn beam_search(
+        &self,
+        text: &str,
+        opts: &PredictOptions,
+        beams: usize,
+        length_penalty: f32,
+        early_stopping: bool,
+    ) -> Result<Generation, Box<dyn Error>> {
+        if beams == 0 {
+            return Err("Beam search needs at least one beam".into());
+        }
+
+        let prompt = self.tokenize_prompt(text)?;
+        let eos = self.token_eos();
+
+        let mut ctx = Context::new(self);
+        ctx.set_n_threads(opts.threads);
+        let n_ctx = ctx.n_ctx();
+
+        if prompt.len() >= n_ctx {
+            return Err("Prompt does not fit in the context".into());
+        }
+
+        let max_tokens = match opts.max_tokens {
+            Some(max_tokens) => (max_tokens as usize).min(n_ctx - prompt.len()),
+            None => n_ctx - prompt.len(),
+        };
+        let n_past = prompt.len() as i32;
+
+        // every beam expands to twice as many candidates so that beams ending this step can
+        // be replaced by live ones
+        let n_candidates = 2 * beams;
+
+        ctx.clear_kv_cache();
+
+        let last = ctx.decode_tokens(&prompt, 0, 0)?;
+
+        let mut live = vec![Beam {
+            seq_id: 0,
+            tokens: vec![],
+            text: String::new(),
+            logprob: 0.0,
+            next: top_logprobs(ctx.logits(last), n_candidates),
+        }];
+        let mut finished: Vec<Hypothesis> = vec![];
+
+        let normalize =
+            |logprob: f32, len: usize| logprob / (len.max(1) as f32).powf(length_penalty);
+
+        let mut batch = Batch::with_capacity(beams);
+        let mut cancelled = false;
+
+        for step in 0..max_tokens {
+            if opts.is_cancelled() {
+                cancelled = true;
+                break;
+            }
+
+            let mut candidates: Vec<(usize, i32, f32)> = live
+                .iter()
+                .enumerate()
+                .flat_map(|(parent, beam)| {
+                    beam.next
+                        .iter()
+                        .map(move |&(token, logprob)| (parent, token, beam.logprob + logprob))
+                })
+                .collect();
+            candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+            // beams alternate between two banks of sequence ids so that a parent's cache is
+            // still around while its children are copied from it
+            let bank = if step % 2 == 0 { beams as i32 } else { 0 };
+            let mut next_live: Vec<Beam> = Vec::with_capacity(beams);
+
+            for (parent, token, logprob) in candidates {
+                if next_live.len() == beams {
+                    break;
+                }
+
+                let parent = &live[parent];
+                let mut tokens = parent.tokens.clone();
+                tokens.push(token);
+
+                if token == eos {
+                    if !opts.ignore_eos {
+                        finished.push(Hypothesis {
+                            text: parent.text.clone(),
+                            len: tokens.len(),
+                            logprob,
+                            stop_reason: StopReason::EndOfText,
+                        });
+                    }
+                    continue;
+                }
+
+                let text = parent.text.clone() + &self.token_to_piece(token);
+
+                if let Some(stop) = opts
+                    .stop_prompts
+                    .iter()
+                    .find(|s| text.ends_with(s.as_str()))
+                {
+                    finished.push(Hypothesis {
+                        text: text.trim_end_matches(stop.as_str()).to_string(),
+                        len: tokens.len(),
+                        logprob,
+                        stop_reason: StopReason::StopPrompt,
+                    });
+                    continue;
+                }
+
+                next_live.push(Beam {
+                    seq_id: parent.seq_id,
+                    tokens,
+                    text,
+                    logprob,
+                    next: vec![],
+                });
+            }
+
+            batch.clear();
+
+            for (i, beam) in next_live.iter_mut().enumerate() {
+                let seq_id = bank + i as i32;
+
+                ctx.kv_cache_seq_rm(seq_id, -1, -1);
+                ctx.kv_cache_seq_cp(beam.seq_id, seq_id, -1, -1);
+
+                beam.seq_id = seq_id;
+                batch.add(
+                    *beam.tokens.last().unwrap(),
+                    n_past + beam.tokens.len() as i32 - 1,
+                    &[seq_id],
+                    true,
+                );
+            }
+
+            for beam in &live {
+                ctx.kv_cache_seq_rm(beam.seq_id, -1, -1);
+            }
+
+            live = next_live;
+
+            if live.is_empty() {
+                break;
+            }
+
+            if finished.len() >= beams {
+                if early_stopping {
+                    break;
+                }
+
+                // stop once no live beam can beat the worst of the best finished hypotheses
+                let mut scores: Vec<f32> = finished
+                    .iter()
+                    .map(|h| normalize(h.logprob, h.len))
+                    .collect();
+                scores.sort_by(|a, b| b.total_cmp(a));
+
+                let best_live = live
+                    .iter()
+                    .map(|b| normalize(b.logprob, b.tokens.len()))
+                    .fold(f32::NEG_INFINITY, f32::max);
+
+                if best_live < scores[beams - 1] {
+                    break;
+                }
+            }
+
+            // the last step only needs to pick the beams, not expand them
+            if step + 1 == max_tokens {
+                break;
+            }
+
+            ctx.decode(&batch)?;
+
+            for (i, beam) in live.iter_mut().enumerate() {
+                beam.next = top_logprobs(ctx.logits(i), n_candidates);
+            }
+        }
+
+        finished.extend(live.into_iter().map(|beam| Hypothesis {
+            text: beam.text,
+            len: beam.tokens.len(),
+            logprob: beam.logprob,
+            stop_reason: if cancelled {
+                StopReason::Callback
+            } else {
+                StopReason::Length
+            },
+        }));
+
+        finished
+            .sort_by(|a, b| normalize(b.logprob, b.len).total_cmp(&normalize(a.logprob, a.len)));
+        finished.truncate(beams);
+
+        let stats = GenerationStats {
+            prompt_tokens: prompt.len(),
+            generated_tokens: finished.iter().map(|h| h.len).sum(),
+            ..Default::default()
+        };
+
+        Ok(Generation {
+            completions: finished
+                .into_iter()
+                .map(|h| Completion {
+                    text: h.text.trim_start_matches('\n').to_string(),
+                    score: Some(h.logprob),
+                    stop_reason: h.stop_reason,
+                })
+                .collect(),
+            stats,
+            ..Default::default()
+        })
+    }
+}
+
+pub(crate) fn base_seed(seed: i32) -> u64 {
+    if seed <= 0 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    } else {
+        seed as u64
+    }
+}
+
+// a sampled sequence driven from rust, `logits_idx` points at the batch entry holding the
+// logits its next token is sampled from
+pub(crate) struct Sequence {
+    seeds: SeedSequence,
+    history: Vec<i32>,
+    text: String,
+    score: f32,
+    n_generated: usize,
+    mirostat_mu: f32,
+    pub(crate) logits_idx: usize,
+    pub(crate) stop_reason: Option<StopReason>,
+}
+
+impl Sequence {
+    pub(crate) fn new(prompt: &[i32], seed: u64, opts: &PredictOptions, logits_idx: usize) -> Self {
+        Self {
+            seeds: SeedSequence(seed),
+            history: prompt.to_vec(),
+            text: String::new(),
+            score: 0.0,
+            n_generated: 0,
+            mirostat_mu: 2.0 * opts.mirostat_tau,
+            logits_idx,
+            stop_reason: None,
+        }
+    }
+
+    // samples and appends the next token, returning its text unless the sequence stopped
+    pub(crate) fn step(
+        &mut self,
+        llama: &LLama,
+        ctx: &Context,
+        params: &Params,
+        opts: &PredictOptions,
+        eos: i32,
+    ) -> Option<String> {
+        if opts.is_cancelled() {
+            self.stop_reason = Some(StopReason::Callback);
+            return None;
+        }
+
+        // only reached here with a limit of 0, otherwise the limit stops the sequence right
+        // after its last token
+        if opts
+            .max_tokens
+            .is_some_and(|max_tokens| self.n_generated >= max_tokens as usize)
+        {
+            self.stop_reason = Some(StopReason::Length);
+            return None;
+        }
+
+        let token = unsafe {
+            llama_binding_sample(
+                params.0,
+                llama.state,
+                self.logits_idx as i32,
+                self.history.as_ptr(),
+                self.history.len() as i32,
+                &mut self.mirostat_mu,
+                self.seeds.next(),
+            )
+        };
+
+        self.score += log_softmax(ctx.logits(self.logits_idx), token);
+        self.n_generated += 1;
+
+        if token == eos {
+            self.stop_reason = Some(StopReason::EndOfText);
+            return None;
+        }
+
+        let piece = llama.token_to_piece(token);
+
+        self.history.push(token);
+        self.text.push_str(&piece);
+
+        if let Some(pos) = opts
+            .stop_prompts
+            .iter()
+            .filter_map(|s| self.text.find(s.as_str()))
+            .min()
+        {
+            self.text.truncate(pos);
+            self.stop_reason = Some(StopReason::StopPrompt);
+            return None;
+        }
+
+        if opts
+            .max_tokens
+            .is_some_and(|max_tokens| self.n_generated >= max_tokens as usize)
+        {
+            self.stop_reason = Some(StopReason::Length);
+            return None;
+        }
+
+        Some(piece)
+    }
+
+    pub(crate) fn n_generated(&self) -> usize {
+        self.n_generated
+    }
+
+    pub(crate) fn history(&self) -> &[i32] {
+        &self.history
+    }
+
+    pub(crate) fn last_token(&self) -> i32 {
+        *self.history.last().unwrap()
+    }
+
+    pub(crate) fn into_completion(self) -> Completion {
+        Completion {
+            text: self.text.trim_start_matches('\n').to_string(),
+            score: Some(self.score),
+            stop_reason: self.stop_reason.unwrap_or(StopReason::Length),
+        }
+    }
+}
+
+// splitmix64, used to give every sequence its own stream of seeds for llama.cpp's sampler
+struct SeedSequence(u64);
+
+impl SeedSequence {
+    fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+
+        (z ^ (z >> 31)) as u32
+    }
+}
+
+struct Beam {
+    seq_id: i32,
+    tokens: Vec<i32>,
+    text: String,
+    logprob: f32,
+    next: Vec<(i32, f32)>,
+}
+
+struct Hypothesis {
+    text: String,
+    len: usize,
+    logprob: f32,
+    stop_reason: StopReason,
+}
+
+// returns the `k` most likely tokens with their log-probabilities
+pub(crate) fn top_logprobs(logits: &[f32], k: usize) -> Vec<(i32, f32)> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits
+        .iter()
+        .map(|&l| ((l - max) as f64).exp())
+        .sum::<f64>()
+        .ln() as f32;
+
+    let mut top: Vec<(i32, f32)> = logits
+        .iter()
+        .enumerate()
+        .map(|(token, &logit)| (token as i32, logit))
+        .collect();
+
+    let k = k.min(top.len());
+    if k < top.len() {
+        top.select_nth_unstable_by(k, |a, b| b.1.total_cmp(&a.1));
+        top.truncate(k);
+    }
+    top.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+    top.into_iter()
+        .map(|(token, logit)| (token, logit - max - log_sum))
+        .collect()
+}
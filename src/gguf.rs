@@ -0,0 +1,701 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::File,
+    io::{BufReader, Read, Seek},
+    path::Path,
+};
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: u64 = 32;
+
+// upper bound for the capacity reserved up front, counts come from the file and may be bogus
+const MAX_PREALLOCATE: usize = 1 << 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GgufValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    F32(f32),
+    Bool(bool),
+    String(String),
+    Array(Vec<GgufValue>),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl GgufValue {
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            GgufValue::U8(v) => Some(v as u64),
+            GgufValue::U16(v) => Some(v as u64),
+            GgufValue::U32(v) => Some(v as u64),
+            GgufValue::U64(v) => Some(v),
+            GgufValue::I8(v) => u64::try_from(v).ok(),
+            GgufValue::I16(v) => u64::try_from(v).ok(),
+            GgufValue::I32(v) => u64::try_from(v).ok(),
+            GgufValue::I64(v) => u64::try_from(v).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            GgufValue::F32(v) => Some(v as f64),
+            GgufValue::F64(v) => Some(v),
+            _ => self.as_u64().map(|v| v as f64),
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match *self {
+            GgufValue::Bool(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            GgufValue::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[GgufValue]> {
+        match self {
+            GgufValue::Array(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GgufTensorInfo {
+    pub name: String,
+    pub dimensions: Vec<u64>,
+    // a ggml_type, the storage type of the tensor data
+    pub ggml_type: u32,
+    // relative to the start of the tensor data
+    pub offset: u64,
+}
+
+impl GgufTensorInfo {
+    // None if the dimensions don't fit in a u64, which GgufFile::read rejects
+    pub fn n_elements(&self) -> Option<u64> {
+        self.dimensions
+            .iter()
+            .try_fold(1u64, |n, dim| n.checked_mul(*dim))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RopeSettings {
+    pub dimension_count: Option<u64>,
+    pub freq_base: Option<f64>,
+    pub scaling_type: Option<String>,
+    pub scaling_factor: Option<f64>,
+    pub original_context_length: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GgufMetadata {
+    pub architecture: Option<String>,
+    pub name: Option<String>,
+    pub license: Option<String>,
+    pub parameter_count: u64,
+    pub context_length: Option<u64>,
+    pub embedding_length: Option<u64>,
+    pub block_count: Option<u64>,
+    pub head_count: Option<u64>,
+    // a llama_ftype, see file_type_name
+    pub file_type: Option<u32>,
+    pub tokenizer_model: Option<String>,
+    pub chat_template: Option<String>,
+    pub rope: RopeSettings,
+}
+
+impl GgufMetadata {
+    pub fn file_type_name(&self) -> Option<&'static str> {
+        let name = match self.file_type? {
+            0 => "F32",
+            1 => "F16",
+            2 => "Q4_0",
+            3 => "Q4_1",
+            4 => "Q4_1_SOME_F16",
+            7 => "Q8_0",
+            8 => "Q5_0",
+            9 => "Q5_1",
+            10 => "Q2_K",
+            11 => "Q3_K_S",
+            12 => "Q3_K_M",
+            13 => "Q3_K_L",
+            14 => "Q4_K_S",
+            15 => "Q4_K_M",
+            16 => "Q5_K_S",
+            17 => "Q5_K_M",
+            18 => "Q6_K",
+            _ => return None,
+        };
+
+        Some(name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GgufFile {
+    pub version: u32,
+    pub kv: HashMap<String, GgufValue>,
+    pub tensors: Vec<GgufTensorInfo>,
+    // absolute file offset of the tensor data
+    pub data_offset: u64,
+    // elements across all tensors, summed while reading
+    pub parameter_count: u64,
+}
+
+impl GgufFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        let file = File::open(path.as_ref())
+            .map_err(|e| format!("Failed to open {}: {}", path.as_ref().display(), e))?;
+
+        Self::read(&mut BufReader::new(file))
+    }
+
+    // reads the header, metadata and tensor descriptors, the tensor data itself is never read
+    pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self, Box<dyn Error>> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != GGUF_MAGIC {
+            return Err("Not a GGUF file".into());
+        }
+
+        let version = read_u32(reader)?;
+
+        if !(1..=3).contains(&version) {
+            return Err(format!("Unsupported GGUF version {}", version).into());
+        }
+
+        let mut r = GgufReader { reader, version };
+
+        let n_tensors = r.read_count()?;
+        let n_kv = r.read_count()?;
+
+        let mut kv = HashMap::with_capacity(n_kv.min(MAX_PREALLOCATE));
+        for _ in 0..n_kv {
+            let key = r.read_string()?;
+            let value_type = read_u32(r.reader)?;
+            let value = r.read_value(value_type)?;
+
+            kv.insert(key, value);
+        }
+
+        let mut tensors = Vec::with_capacity(n_tensors.min(MAX_PREALLOCATE));
+        let mut parameter_count = 0u64;
+        for _ in 0..n_tensors {
+            let name = r.read_string()?;
+            let n_dims = read_u32(r.reader)?;
+
+            let mut dimensions = Vec::with_capacity(n_dims.min(4) as usize);
+            for _ in 0..n_dims {
+                dimensions.push(r.read_dimension()?);
+            }
+
+            let tensor = GgufTensorInfo {
+                name,
+                dimensions,
+                ggml_type: read_u32(r.reader)?,
+                offset: read_u64(r.reader)?,
+            };
+
+            parameter_count = tensor
+                .n_elements()
+                .and_then(|n| parameter_count.checked_add(n))
+                .ok_or_else(|| format!("GGUF tensor {} has too many elements", tensor.name))?;
+
+            tensors.push(tensor);
+        }
+
+        let alignment = kv
+            .get("general.alignment")
+            .and_then(GgufValue::as_u64)
+            .filter(|a| *a > 0)
+            .unwrap_or(DEFAULT_ALIGNMENT);
+
+        let position = r.reader.stream_position()?;
+        let data_offset = position.div_ceil(alignment) * alignment;
+
+        Ok(Self {
+            version,
+            kv,
+            tensors,
+            data_offset,
+            parameter_count,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&GgufValue> {
+        self.kv.get(key)
+    }
+
+    pub fn metadata(&self) -> GgufMetadata {
+        let architecture = self.get_string("general.architecture");
+        let arch = architecture.clone().unwrap_or_default();
+        let arch_u64 = |key: &str| self.get_u64(&format!("{}.{}", arch, key));
+
+        GgufMetadata {
+            name: self.get_string("general.name"),
+            license: self.get_string("general.license"),
+            parameter_count: self.parameter_count,
+            context_length: arch_u64("context_length"),
+            embedding_length: arch_u64("embedding_length"),
+            block_count: arch_u64("block_count"),
+            head_count: arch_u64("attention.head_count"),
+            file_type: self
+                .get_u64("general.file_type")
+                .and_then(|t| u32::try_from(t).ok()),
+            tokenizer_model: self.get_string("tokenizer.ggml.model"),
+            chat_template: self.get_string("tokenizer.chat_template"),
+            rope: RopeSettings {
+                dimension_count: arch_u64("rope.dimension_count"),
+                freq_base: self
+                    .get(&format!("{}.rope.freq_base", arch))
+                    .and_then(GgufValue::as_f64),
+                scaling_type: self.get_string(&format!("{}.rope.scaling.type", arch)),
+                scaling_factor: self
+                    .get(&format!("{}.rope.scaling.factor", arch))
+                    .and_then(GgufValue::as_f64),
+                original_context_length: arch_u64("rope.scaling.original_context_length"),
+            },
+            architecture,
+        }
+    }
+
+    fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(GgufValue::as_u64)
+    }
+
+    fn get_string(&self, key: &str) -> Option<String> {
+        self.get(key).and_then(GgufValue::as_str).map(String::from)
+    }
+}
+
+struct GgufReader<'a, R> {
+    reader: &'a mut R,
+    version: u32,
+}
+
+impl<R: Read> GgufReader<'_, R> {
+    // version 1 files use 32 bit counts and lengths, later versions 64 bit
+    fn read_count(&mut self) -> Result<usize, Box<dyn Error>> {
+        let count = if self.version == 1 {
+            read_u32(self.reader)? as u64
+        } else {
+            read_u64(self.reader)?
+        };
+
+        usize::try_from(count).map_err(|_| "GGUF count does not fit in memory".into())
+    }
+
+    fn read_dimension(&mut self) -> Result<u64, Box<dyn Error>> {
+        if self.version == 1 {
+            Ok(read_u32(self.reader)? as u64)
+        } else {
+            read_u64(self.reader)
+        }
+    }
+
+    fn read_string(&mut self) -> Result<String, Box<dyn Error>> {
+        let len = self.read_count()?;
+
+        let mut buf = Vec::with_capacity(len.min(MAX_PREALLOCATE));
+        self.reader.take(len as u64).read_to_end(&mut buf)?;
+
+        if buf.len() != len {
+            return Err("Unexpected end of GGUF file".into());
+        }
+
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn read_value(&mut self, value_type: u32) -> Result<GgufValue, Box<dyn Error>> {
+        let value = match value_type {
+            0 => GgufValue::U8(read_array::<1>(self.reader)?[0]),
+            1 => GgufValue::I8(read_array::<1>(self.reader)?[0] as i8),
+            2 => GgufValue::U16(u16::from_le_bytes(read_array(self.reader)?)),
+            3 => GgufValue::I16(i16::from_le_bytes(read_array(self.reader)?)),
+            4 => GgufValue::U32(read_u32(self.reader)?),
+            5 => GgufValue::I32(i32::from_le_bytes(read_array(self.reader)?)),
+            6 => GgufValue::F32(f32::from_le_bytes(read_array(self.reader)?)),
+            7 => GgufValue::Bool(read_array::<1>(self.reader)?[0] != 0),
+            8 => GgufValue::String(self.read_string()?),
+            9 => {
+                let item_type = read_u32(self.reader)?;
+                let len = self.read_count()?;
+
+                if item_type == 9 {
+                    return Err("Nested GGUF arrays are not supported".into());
+                }
+
+                let mut items = Vec::with_capacity(len.min(MAX_PREALLOCATE));
+                for _ in 0..len {
+                    items.push(self.read_value(item_type)?);
+                }
+
+                GgufValue::Array(items)
+            }
+            10 => GgufValue::U64(read_u64(self.reader)?),
+            11 => GgufValue::I64(i64::from_le_bytes(read_array(self.reader)?)),
+            12 => GgufValue::F64(f64::from_le_bytes(read_array(self.reader)?)),
+            _ => return Err(format!("Unknown GGUF value type {}", value_type).into()),
+        };
+
+        Ok(value)
+    }
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> Result<[u8; N], Box<dyn Error>> {
+    let mut buf = [0u8; N];
+    reader.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, Box<dyn Error>> {
+    Ok(u32::from_le_bytes(read_array(reader)?))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64, Box<dyn Error>> {
+    Ok(u64::from_le_bytes(read_array(reader)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    // writes GGUF headers the way llama.cpp lays them out for the given version
+    struct Writer {
+        version: u32,
+        buf: Vec<u8>,
+    }
+
+    impl Writer {
+        fn new(version: u32, n_tensors: u64, n_kv: u64) -> Self {
+            let mut w = Self {
+                version,
+                buf: GGUF_MAGIC.to_vec(),
+            };
+
+            w.u32(version);
+            w.count(n_tensors);
+            w.count(n_kv);
+
+            w
+        }
+
+        fn u32(&mut self, v: u32) {
+            self.buf.extend_from_slice(&v.to_le_bytes());
+        }
+
+        fn u64(&mut self, v: u64) {
+            self.buf.extend_from_slice(&v.to_le_bytes());
+        }
+
+        fn count(&mut self, n: u64) {
+            if self.version == 1 {
+                self.u32(n as u32);
+            } else {
+                self.u64(n);
+            }
+        }
+
+        fn string(&mut self, s: &str) {
+            self.count(s.len() as u64);
+            self.buf.extend_from_slice(s.as_bytes());
+        }
+
+        fn kv(&mut self, key: &str, value: &GgufValue) {
+            self.string(key);
+            self.u32(value_type(value));
+            self.value(value);
+        }
+
+        fn value(&mut self, value: &GgufValue) {
+            match value {
+                GgufValue::U8(v) => self.buf.push(*v),
+                GgufValue::I8(v) => self.buf.push(*v as u8),
+                GgufValue::U16(v) => self.buf.extend_from_slice(&v.to_le_bytes()),
+                GgufValue::I16(v) => self.buf.extend_from_slice(&v.to_le_bytes()),
+                GgufValue::U32(v) => self.u32(*v),
+                GgufValue::I32(v) => self.buf.extend_from_slice(&v.to_le_bytes()),
+                GgufValue::F32(v) => self.buf.extend_from_slice(&v.to_le_bytes()),
+                GgufValue::Bool(v) => self.buf.push(*v as u8),
+                GgufValue::String(v) => self.string(v),
+                GgufValue::Array(items) => {
+                    self.u32(items.first().map(value_type).unwrap_or(0));
+                    self.count(items.len() as u64);
+
+                    for item in items {
+                        self.value(item);
+                    }
+                }
+                GgufValue::U64(v) => self.u64(*v),
+                GgufValue::I64(v) => self.buf.extend_from_slice(&v.to_le_bytes()),
+                GgufValue::F64(v) => self.buf.extend_from_slice(&v.to_le_bytes()),
+            }
+        }
+
+        fn tensor(&mut self, name: &str, dimensions: &[u64], ggml_type: u32, offset: u64) {
+            self.string(name);
+            self.u32(dimensions.len() as u32);
+
+            for dim in dimensions {
+                self.count(*dim);
+            }
+
+            self.u32(ggml_type);
+            self.u64(offset);
+        }
+    }
+
+    fn value_type(value: &GgufValue) -> u32 {
+        match value {
+            GgufValue::U8(_) => 0,
+            GgufValue::I8(_) => 1,
+            GgufValue::U16(_) => 2,
+            GgufValue::I16(_) => 3,
+            GgufValue::U32(_) => 4,
+            GgufValue::I32(_) => 5,
+            GgufValue::F32(_) => 6,
+            GgufValue::Bool(_) => 7,
+            GgufValue::String(_) => 8,
+            GgufValue::Array(_) => 9,
+            GgufValue::U64(_) => 10,
+            GgufValue::I64(_) => 11,
+            GgufValue::F64(_) => 12,
+        }
+    }
+
+    fn read(buf: &[u8]) -> Result<GgufFile, Box<dyn Error>> {
+        GgufFile::read(&mut Cursor::new(buf))
+    }
+
+    fn every_value() -> Vec<(&'static str, GgufValue)> {
+        vec![
+            ("test.u8", GgufValue::U8(200)),
+            ("test.i8", GgufValue::I8(-100)),
+            ("test.u16", GgufValue::U16(60000)),
+            ("test.i16", GgufValue::I16(-30000)),
+            ("test.u32", GgufValue::U32(4_000_000_000)),
+            ("test.i32", GgufValue::I32(-2_000_000_000)),
+            ("test.f32", GgufValue::F32(0.5)),
+            ("test.bool", GgufValue::Bool(true)),
+            ("test.string", GgufValue::String("héllo".to_string())),
+            (
+                "test.array",
+                GgufValue::Array(vec![GgufValue::U32(1), GgufValue::U32(2)]),
+            ),
+            (
+                "test.strings",
+                GgufValue::Array(vec![
+                    GgufValue::String("a".to_string()),
+                    GgufValue::String("bc".to_string()),
+                ]),
+            ),
+            ("test.empty", GgufValue::Array(vec![])),
+            ("test.u64", GgufValue::U64(u64::MAX)),
+            ("test.i64", GgufValue::I64(i64::MIN)),
+            ("test.f64", GgufValue::F64(-1.25)),
+        ]
+    }
+
+    // a small llama-like model with every value type, a valid header for the version
+    fn model(version: u32) -> Vec<u8> {
+        let values = every_value();
+
+        let mut kv = vec![
+            (
+                "general.architecture",
+                GgufValue::String("llama".to_string()),
+            ),
+            ("general.name", GgufValue::String("tiny".to_string())),
+            ("general.file_type", GgufValue::U32(15)),
+            ("llama.context_length", GgufValue::U32(4096)),
+            ("llama.embedding_length", GgufValue::U64(64)),
+            ("llama.rope.freq_base", GgufValue::F32(10000.0)),
+            (
+                "tokenizer.ggml.model",
+                GgufValue::String("llama".to_string()),
+            ),
+        ];
+        kv.extend(values);
+
+        let mut w = Writer::new(version, 2, kv.len() as u64);
+
+        for (key, value) in &kv {
+            w.kv(key, value);
+        }
+
+        w.tensor("token_embd.weight", &[64, 100], 12, 0);
+        w.tensor("output_norm.weight", &[64], 0, 3520);
+
+        w.buf
+    }
+
+    #[test]
+    fn reads_every_version() {
+        for version in 1..=3 {
+            let buf = model(version);
+            let file = read(&buf).unwrap();
+
+            assert_eq!(file.version, version);
+
+            for (key, value) in every_value() {
+                assert_eq!(
+                    file.get(key),
+                    Some(&value),
+                    "{} in version {}",
+                    key,
+                    version
+                );
+            }
+
+            assert_eq!(
+                file.tensors,
+                vec![
+                    GgufTensorInfo {
+                        name: "token_embd.weight".to_string(),
+                        dimensions: vec![64, 100],
+                        ggml_type: 12,
+                        offset: 0,
+                    },
+                    GgufTensorInfo {
+                        name: "output_norm.weight".to_string(),
+                        dimensions: vec![64],
+                        ggml_type: 0,
+                        offset: 3520,
+                    },
+                ]
+            );
+
+            assert_eq!(file.data_offset, (buf.len() as u64).div_ceil(32) * 32);
+        }
+    }
+
+    #[test]
+    fn metadata() {
+        let metadata = read(&model(3)).unwrap().metadata();
+
+        assert_eq!(metadata.architecture.as_deref(), Some("llama"));
+        assert_eq!(metadata.name.as_deref(), Some("tiny"));
+        assert_eq!(metadata.parameter_count, 64 * 100 + 64);
+        assert_eq!(metadata.context_length, Some(4096));
+        assert_eq!(metadata.embedding_length, Some(64));
+        assert_eq!(metadata.block_count, None);
+        assert_eq!(metadata.file_type_name(), Some("Q4_K_M"));
+        assert_eq!(metadata.tokenizer_model.as_deref(), Some("llama"));
+        assert_eq!(metadata.rope.freq_base, Some(10000.0));
+    }
+
+    #[test]
+    fn alignment() {
+        let mut w = Writer::new(3, 0, 1);
+        w.kv("general.alignment", &GgufValue::U32(4096));
+
+        let file = read(&w.buf).unwrap();
+
+        assert_eq!(file.data_offset, 4096);
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        assert!(read(b"GGML\x03\x00\x00\x00").is_err());
+        assert!(read(&Writer::new(0, 0, 0).buf).is_err());
+        assert!(read(&Writer::new(4, 0, 0).buf).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        for version in 1..=3 {
+            let buf = model(version);
+
+            for len in 0..buf.len() {
+                assert!(
+                    read(&buf[..len]).is_err(),
+                    "{} of {} bytes in version {}",
+                    len,
+                    buf.len(),
+                    version
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_absurd_counts() {
+        for version in 1..=3 {
+            let max = if version == 1 {
+                u32::MAX as u64
+            } else {
+                u64::MAX
+            };
+
+            assert!(read(&Writer::new(version, max, 0).buf).is_err());
+            assert!(read(&Writer::new(version, 0, max).buf).is_err());
+
+            // key length
+            let mut w = Writer::new(version, 0, 1);
+            w.count(max);
+            assert!(read(&w.buf).is_err());
+
+            // array length
+            let mut w = Writer::new(version, 0, 1);
+            w.string("test.array");
+            w.u32(9);
+            w.u32(4);
+            w.count(max);
+            assert!(read(&w.buf).is_err());
+
+            // dimension count
+            let mut w = Writer::new(version, 1, 0);
+            w.string("tensor");
+            w.u32(u32::MAX);
+            assert!(read(&w.buf).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_dimensions() {
+        let mut w = Writer::new(3, 1, 0);
+        w.tensor("tensor", &[u64::MAX, 2], 0, 0);
+        let err = read(&w.buf).unwrap_err();
+        assert!(err.to_string().contains("too many elements"), "{}", err);
+
+        let mut w = Writer::new(3, 2, 0);
+        w.tensor("a", &[1 << 63], 0, 0);
+        w.tensor("b", &[1 << 63], 0, 0);
+        let err = read(&w.buf).unwrap_err();
+        assert!(err.to_string().contains("too many elements"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_bad_value_types() {
+        let mut w = Writer::new(3, 0, 1);
+        w.string("test.unknown");
+        w.u32(13);
+        assert!(read(&w.buf).is_err());
+
+        let mut w = Writer::new(3, 0, 1);
+        w.string("test.nested");
+        w.u32(9);
+        w.u32(9);
+        w.count(1);
+        assert!(read(&w.buf).is_err());
+    }
+}
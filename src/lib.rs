@@ -2,43 +2,91 @@ use std::{
     collections::HashMap,
     error::Error,
     ffi::{c_char, c_void, CStr, CString},
-    mem::size_of,
+    path::Path,
+    ptr,
     sync::Mutex,
+    thread,
 };
 
-use options::{ModelOptions, PredictOptions};
+use generation::{Completion, Generation, GenerationStats, StopReason};
+use load::{LoadCancelled, LoadState};
+use metrics::ErrorKind;
+use options::{
+    parse_logit_bias, GenerationMode, LoraAdapter, ModelOptions, OptionsError, PredictOptions,
+    TokenCallback,
+};
+use params::Params;
 
 use lazy_static::lazy_static;
 
+pub mod cancel;
+#[cfg(feature = "serde")]
+mod config;
+pub mod context;
+pub mod generation;
+pub mod gguf;
+pub mod load;
+mod logging;
+#[cfg(target_os = "linux")]
+mod memory;
+pub mod metrics;
+pub mod model;
 pub mod options;
+mod params;
+mod quantize;
+pub mod scheduler;
+pub mod scoring;
+pub mod speculative;
+pub mod timings;
+
+pub use quantize::quantize;
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
 
 lazy_static! {
-    static ref CALLBACKS: Mutex<HashMap<usize, Box<dyn Fn(String) -> bool + Send + 'static>>> =
-        Mutex::new(HashMap::new());
+    static ref CALLBACKS: Mutex<HashMap<usize, TokenCallback>> = Mutex::new(HashMap::new());
 }
 
-#[derive(Debug, Clone)]
+// not Clone, the handle owns the context and frees it when dropped
+#[derive(Debug)]
 pub struct LLama {
     state: *mut c_void,
+    // the model's file name, used as the model label for metrics
+    name: String,
     embeddings: bool,
     context_size: i32,
+    n_batch: i32,
+    m_map: bool,
+    lora_adapters: Vec<LoraAdapter>,
 }
 
 impl LLama {
     pub fn new(model: String, opts: &ModelOptions) -> Result<Self, Box<dyn Error>> {
-        let model_path = CString::new(model).unwrap();
+        opts.validate()?;
+
+        let mut progress = opts
+            .progress_callback
+            .as_ref()
+            .map(|callback| LoadState::new(&model, callback));
+
+        let progress_ptr = match &mut progress {
+            Some(state) => state as *mut LoadState as *mut c_void,
+            None => ptr::null_mut(),
+        };
 
-        let main_gpu_cstr = CString::new(opts.main_gpu.clone()).unwrap();
+        let name = Path::new(&model)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
 
-        let main_gpu = main_gpu_cstr.as_ptr();
+        let model_path = CString::new(model).unwrap();
 
-        let tensor_split_cstr = CString::new(opts.tensor_split.clone()).unwrap();
+        let tensor_split = opts.gpu.effective_tensor_split();
 
-        let tensor_split = tensor_split_cstr.as_ptr();
+        // adapters are merged into the weights, which a read-only mapping doesn't allow
+        let m_map = opts.m_map && opts.lora_adapters.is_empty();
 
-        unsafe {
+        let mut llama = unsafe {
             let result = load_model(
                 model_path.as_ptr(),
                 opts.context_size,
@@ -46,149 +94,196 @@ impl LLama {
                 opts.f16_memory,
                 opts.m_lock,
                 opts.embeddings,
-                opts.m_map,
+                m_map,
                 opts.low_vram,
                 opts.vocab_only,
-                opts.n_gpu_layers,
+                opts.gpu.n_gpu_layers.count(),
                 opts.n_batch,
-                main_gpu,
-                tensor_split,
+                opts.gpu.main_gpu as i32,
+                tensor_split.as_ptr(),
+                tensor_split.len() as i32,
                 opts.numa,
+                progress_ptr,
             );
 
+            if progress.is_some_and(|state| state.cancelled) {
+                return Err(LoadCancelled.into());
+            }
+
             if result == std::ptr::null_mut() {
                 return Err("Failed to load model".into());
             } else {
-                Ok(Self {
+                Self {
                     state: result,
+                    name,
                     embeddings: opts.embeddings,
                     context_size: opts.context_size,
-                })
+                    // 0 leaves llama.cpp's default batch size in place
+                    n_batch: if opts.n_batch > 0 { opts.n_batch } else { 512 },
+                    m_map,
+                    lora_adapters: vec![],
+                }
             }
-        }
-    }
+        };
 
-    pub fn free_model(&self) {
-        unsafe {
-            llama_binding_free_model(self.state);
+        // on failure dropping llama frees the model
+        for adapter in &opts.lora_adapters {
+            llama.apply_lora(adapter.clone())?;
         }
+
+        Ok(llama)
     }
 
-    pub fn load_state(&self, state: String) -> Result<(), Box<dyn Error>> {
-        let d = CString::new(state).unwrap().into_raw();
-        let w = CString::new("rb").unwrap().into_raw();
+    // PredictOptions::validate plus the checks that depend on the loaded model
+    pub(crate) fn validate_predict(&self, opts: &PredictOptions) -> Result<(), OptionsError> {
+        let n_ctx = unsafe { llama_binding_n_ctx(self.state) };
 
-        unsafe {
-            let result = load_state(self.state, d, w);
+        let result = opts.validate().and_then(|_| {
+            if opts.batch > n_ctx {
+                return Err(OptionsError::BatchLargerThanContext {
+                    n_batch: opts.batch,
+                    n_ctx,
+                });
+            }
 
-            if result != 0 {
-                return Err("Failed to load state".into());
-            } else {
-                Ok(())
+            // decoded one token per sequence, beam or draft in a single batch, llama_decode
+            // asserts that it fits
+            let per_batch = match opts.mode {
+                GenerationMode::BeamSearch { beams, .. } => Some(("beams", beams)),
+                GenerationMode::Sampling if opts.n_completions > 1 => {
+                    Some(("n_completions", opts.n_completions))
+                }
+                GenerationMode::Sampling => opts
+                    .prompt_lookup
+                    .map(|lookup| ("prompt_lookup.n_draft", lookup.n_draft.saturating_add(1))),
+            };
+
+            if let Some((name, n_tokens)) = per_batch {
+                if n_tokens > self.n_batch as usize {
+                    return Err(OptionsError::LargerThanBatch {
+                        name,
+                        n_tokens,
+                        n_batch: self.n_batch,
+                    });
+                }
             }
+
+            // sample_token indexes the logits with the token id
+            if let Some((token, _)) = parse_logit_bias(&opts.logit_bias)? {
+                let n_vocab = self.n_vocab();
+                if token as usize >= n_vocab {
+                    return Err(OptionsError::LogitBiasTokenOutOfRange { token, n_vocab });
+                }
+            }
+
+            Ok(())
+        });
+
+        if result.is_err() {
+            metrics::record_error(&self.name, ErrorKind::InvalidOptions);
         }
+
+        result
     }
 
-    pub fn save_state(&self, dst: String) -> Result<(), Box<dyn Error>> {
-        let d = CString::new(dst.clone()).unwrap().into_raw();
-        let w = CString::new("wb").unwrap().into_raw();
+    pub fn lora_adapters(&self) -> &[LoraAdapter] {
+        &self.lora_adapters
+    }
 
-        unsafe {
-            save_state(self.state, d, w);
-        };
+    pub fn apply_lora(&mut self, adapter: LoraAdapter) -> Result<(), Box<dyn Error>> {
+        if self.m_map {
+            return Err("LoRA adapters can't be applied to a memory-mapped model".into());
+        }
 
-        std::fs::metadata(dst).map_err(|_| "Failed to save state".to_string())?;
+        self.merge_lora(&adapter, adapter.scale)?;
+        self.lora_adapters.push(adapter);
 
         Ok(())
     }
 
-    pub fn eval(&self, text: String, opts: &mut PredictOptions) -> Result<(), Box<dyn Error>> {
-        let c_str = CString::new(text.clone()).unwrap();
+    // the adapter was merged into the weights, removing it merges it again with the opposite
+    // scale, which only restores the original weights exactly for unquantized models
+    pub fn remove_lora(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let Some(i) = self.lora_adapters.iter().position(|a| a.path == path) else {
+            return Err(format!("LoRA adapter {} is not applied", path).into());
+        };
 
-        let input = c_str.as_ptr();
+        let adapter = self.lora_adapters[i].clone();
 
-        let input2 = c_str.into_raw();
+        self.merge_lora(&adapter, -adapter.scale)?;
+        self.lora_adapters.remove(i);
 
-        if opts.tokens == 0 {
-            opts.tokens = 99999999;
-        }
+        Ok(())
+    }
 
-        let reverse_count = opts.stop_prompts.len();
+    fn merge_lora(&self, adapter: &LoraAdapter, scale: f32) -> Result<(), Box<dyn Error>> {
+        let path = CString::new(adapter.path.clone())?;
+        let base_model = CString::new(adapter.base_model.clone().unwrap_or_default())?;
 
-        let mut c_strings: Vec<CString> = Vec::new();
+        let n_threads = thread::available_parallelism()
+            .map(|n| n.get() as i32)
+            .unwrap_or(4);
 
-        let mut reverse_prompt = Vec::with_capacity(reverse_count);
+        unsafe {
+            let result = llama_binding_apply_lora(
+                self.state,
+                path.as_ptr(),
+                scale,
+                base_model.as_ptr(),
+                n_threads,
+            );
 
-        let mut pass: *mut *const c_char = std::ptr::null_mut();
+            if result != 0 {
+                return Err(format!("Failed to apply LoRA adapter {}", adapter.path).into());
+            }
 
-        for prompt in &opts.stop_prompts {
-            let c_string = CString::new(prompt.clone()).unwrap();
-            reverse_prompt.push(c_string.as_ptr());
-            c_strings.push(c_string);
+            // anything cached was computed with the previous weights
+            llama_binding_kv_cache_clear(self.state);
         }
 
-        if !reverse_prompt.is_empty() {
-            pass = reverse_prompt.as_mut_ptr();
-        }
+        Ok(())
+    }
+
+    pub fn load_state(&self, state: String) -> Result<(), Box<dyn Error>> {
+        let path = CString::new(state)?;
 
-        let logit_bias_cstr = CString::new(opts.logit_bias.clone()).unwrap();
+        unsafe {
+            let result = load_state(self.state, path.as_ptr(), c"rb".as_ptr());
 
-        let logit_bias = logit_bias_cstr.as_ptr();
+            if result != 0 {
+                return Err("Failed to load state".into());
+            } else {
+                Ok(())
+            }
+        }
+    }
 
-        let path_prompt_cache_cstr = CString::new(opts.path_prompt_cache.clone()).unwrap();
+    pub fn save_state(&self, dst: String) -> Result<(), Box<dyn Error>> {
+        let path = CString::new(dst)?;
 
-        let path_prompt_cache = path_prompt_cache_cstr.as_ptr();
+        unsafe {
+            let result = save_state(self.state, path.as_ptr(), c"wb".as_ptr());
 
-        let main_gpu_cstr = CString::new(opts.main_gpu.clone()).unwrap();
+            if result != 0 {
+                return Err("Failed to save state".into());
+            }
+        };
 
-        let main_gpu = main_gpu_cstr.as_ptr();
+        Ok(())
+    }
 
-        let tensor_split_cstr = CString::new(opts.tensor_split.clone()).unwrap();
+    pub fn eval(&self, text: String, opts: &PredictOptions) -> Result<(), Box<dyn Error>> {
+        self.validate_predict(opts)?;
 
-        let tensor_split = tensor_split_cstr.as_ptr();
+        let input = CString::new(text.clone())?;
+        let params = Params::new(&text, opts)?;
 
         unsafe {
-            let params = llama_allocate_params(
-                input,
-                opts.seed,
-                opts.threads,
-                opts.tokens,
-                opts.top_k,
-                opts.top_p,
-                opts.temperature,
-                opts.penalty,
-                opts.repeat,
-                opts.ignore_eos,
-                opts.f16_kv,
-                opts.batch,
-                opts.n_keep,
-                pass,
-                reverse_count as i32,
-                opts.tail_free_sampling_z,
-                opts.typical_p,
-                opts.frequency_penalty,
-                opts.presence_penalty,
-                opts.mirostat,
-                opts.mirostat_eta,
-                opts.mirostat_tau,
-                opts.penalize_nl,
-                logit_bias,
-                path_prompt_cache,
-                opts.prompt_cache_all,
-                opts.m_lock,
-                opts.m_map,
-                main_gpu,
-                tensor_split,
-                opts.prompt_cache_ro,
-            );
-
-            let ret = eval(params, self.state, input2);
+            let ret = eval(params.0, self.state, input.as_ptr());
 
             if ret != 0 {
                 return Err("Failed to predict".into());
             }
-
-            llama_free_params(params);
         }
 
         Ok(())
@@ -197,293 +292,203 @@ impl LLama {
     pub fn token_embeddings(
         &self,
         tokens: Vec<i32>,
-        opts: &mut PredictOptions,
+        opts: &PredictOptions,
     ) -> Result<Vec<f32>, Box<dyn Error>> {
         if !self.embeddings {
             return Err("model loaded without embeddings".into());
         }
 
-        if opts.tokens == 0 {
-            opts.tokens = 99999999;
-        }
-
-        let mut out = Vec::with_capacity(opts.tokens as usize);
-
-        let mut my_array: Vec<i32> = Vec::with_capacity(opts.tokens as usize * size_of::<i32>());
-
-        for (i, &v) in tokens.iter().enumerate() {
-            my_array[i] = v;
-        }
-
-        let logit_bias_cstr = CString::new(opts.logit_bias.clone()).unwrap();
+        self.validate_predict(opts)?;
 
-        let logit_bias = logit_bias_cstr.as_ptr();
+        let mut out = vec![0.0; self.n_embd()];
 
-        let path_prompt_cache_cstr = CString::new(opts.path_prompt_cache.clone()).unwrap();
-
-        let path_prompt_cache = path_prompt_cache_cstr.as_ptr();
-
-        let main_gpu_cstr = CString::new(opts.main_gpu.clone()).unwrap();
-
-        let main_gpu = main_gpu_cstr.as_ptr();
-
-        let tensor_split_cstr = CString::new(opts.tensor_split.clone()).unwrap();
-
-        let tensor_split = tensor_split_cstr.as_ptr();
-
-        let input = CString::new("").unwrap();
+        let params = Params::new("", opts)?;
 
         unsafe {
-            let params = llama_allocate_params(
-                input.as_ptr(),
-                opts.seed,
-                opts.threads,
-                opts.tokens,
-                opts.top_k,
-                opts.top_p,
-                opts.temperature,
-                opts.penalty,
-                opts.repeat,
-                opts.ignore_eos,
-                opts.f16_kv,
-                opts.batch,
-                opts.n_keep,
-                std::ptr::null_mut(),
-                0,
-                opts.tail_free_sampling_z,
-                opts.typical_p,
-                opts.frequency_penalty,
-                opts.presence_penalty,
-                opts.mirostat,
-                opts.mirostat_eta,
-                opts.mirostat_tau,
-                opts.penalize_nl,
-                logit_bias,
-                path_prompt_cache,
-                opts.prompt_cache_all,
-                opts.m_lock,
-                opts.m_map,
-                main_gpu,
-                tensor_split,
-                opts.prompt_cache_ro,
-            );
-
             let ret = get_token_embeddings(
-                params,
+                params.0,
                 self.state,
-                my_array.as_mut_ptr(),
-                my_array.len() as i32,
+                tokens.as_ptr(),
+                tokens.len() as i32,
                 out.as_mut_ptr(),
             );
 
             if ret != 0 {
+                metrics::record_error(&self.name, ErrorKind::Embeddings);
                 return Err("Embedding inference failed".into());
             }
-
-            Ok(out)
         }
+
+        metrics::record_embeddings(&self.name);
+
+        Ok(out)
     }
 
     pub fn embeddings(
         &self,
         text: String,
-        opts: &mut PredictOptions,
+        opts: &PredictOptions,
     ) -> Result<Vec<f32>, Box<dyn Error>> {
         if !self.embeddings {
             return Err("model loaded without embeddings".into());
         }
 
-        let c_str = CString::new(text.clone()).unwrap();
-
-        let input = c_str.as_ptr();
-
-        if opts.tokens == 0 {
-            opts.tokens = 99999999;
-        }
-
-        let reverse_count = opts.stop_prompts.len();
-
-        let mut c_strings: Vec<CString> = Vec::new();
+        self.validate_predict(opts)?;
 
-        let mut reverse_prompt = Vec::with_capacity(reverse_count);
+        let mut out = vec![0.0; self.n_embd()];
 
-        let mut pass: *mut *const c_char = std::ptr::null_mut();
+        let params = Params::new(&text, opts)?;
 
-        for prompt in &opts.stop_prompts {
-            let c_string = CString::new(prompt.clone()).unwrap();
-            reverse_prompt.push(c_string.as_ptr());
-            c_strings.push(c_string);
-        }
+        unsafe {
+            let ret = get_embeddings(params.0, self.state, out.as_mut_ptr());
 
-        if !reverse_prompt.is_empty() {
-            pass = reverse_prompt.as_mut_ptr();
+            if ret != 0 {
+                metrics::record_error(&self.name, ErrorKind::Embeddings);
+                return Err("Embedding inference failed".into());
+            }
         }
 
-        let mut out = Vec::with_capacity(opts.tokens as usize);
+        metrics::record_embeddings(&self.name);
 
-        let logit_bias_cstr = CString::new(opts.logit_bias.clone()).unwrap();
+        Ok(out)
+    }
 
-        let logit_bias = logit_bias_cstr.as_ptr();
+    pub fn tokenize(&self, text: &str, add_bos: bool) -> Result<Vec<i32>, Box<dyn Error>> {
+        let c_str = CString::new(text)?;
 
-        let path_prompt_cache_cstr = CString::new(opts.path_prompt_cache.clone()).unwrap();
+        // a token is never shorter than a byte, plus room for BOS
+        let mut tokens: Vec<i32> = vec![0; text.len() + 1];
 
-        let path_prompt_cache = path_prompt_cache_cstr.as_ptr();
+        let n_tokens = unsafe {
+            llama_binding_tokenize(
+                self.state,
+                c_str.as_ptr(),
+                tokens.as_mut_ptr(),
+                tokens.len() as i32,
+                add_bos,
+            )
+        };
 
-        let main_gpu_cstr = CString::new(opts.main_gpu.clone()).unwrap();
+        if n_tokens < 0 {
+            return Err("Failed to tokenize".into());
+        }
 
-        let main_gpu = main_gpu_cstr.as_ptr();
+        tokens.truncate(n_tokens as usize);
 
-        let tensor_split_cstr = CString::new(opts.tensor_split.clone()).unwrap();
+        Ok(tokens)
+    }
 
-        let tensor_split = tensor_split_cstr.as_ptr();
+    pub fn token_to_piece(&self, token: i32) -> String {
+        let mut buf: Vec<u8> = vec![0; 8];
 
         unsafe {
-            let params = llama_allocate_params(
-                input,
-                opts.seed,
-                opts.threads,
-                opts.tokens,
-                opts.top_k,
-                opts.top_p,
-                opts.temperature,
-                opts.penalty,
-                opts.repeat,
-                opts.ignore_eos,
-                opts.f16_kv,
-                opts.batch,
-                opts.n_keep,
-                pass,
-                reverse_count as i32,
-                opts.tail_free_sampling_z,
-                opts.typical_p,
-                opts.frequency_penalty,
-                opts.presence_penalty,
-                opts.mirostat,
-                opts.mirostat_eta,
-                opts.mirostat_tau,
-                opts.penalize_nl,
-                logit_bias,
-                path_prompt_cache,
-                opts.prompt_cache_all,
-                opts.m_lock,
-                opts.m_map,
-                main_gpu,
-                tensor_split,
-                opts.prompt_cache_ro,
+            let mut n = llama_binding_token_to_piece(
+                self.state,
+                token,
+                buf.as_mut_ptr() as *mut c_char,
+                buf.len() as i32,
             );
 
-            let ret = get_embeddings(params, self.state, out.as_mut_ptr());
-
-            if ret != 0 {
-                return Err("Embedding inference failed".into());
+            if n < 0 {
+                buf.resize(-n as usize, 0);
+                n = llama_binding_token_to_piece(
+                    self.state,
+                    token,
+                    buf.as_mut_ptr() as *mut c_char,
+                    buf.len() as i32,
+                );
             }
 
-            Ok(out)
+            buf.truncate(n.max(0) as usize);
         }
+
+        String::from_utf8_lossy(&buf).into_owned()
     }
 
-    pub fn set_token_callback(
-        &self,
-        callback: Option<Box<dyn Fn(String) -> bool + Send + 'static>>,
-    ) {
+    pub fn set_token_callback(&self, callback: Option<TokenCallback>) {
         set_callback(self.state, callback);
     }
 
     pub fn predict(&self, text: String, opts: PredictOptions) -> Result<String, Box<dyn Error>> {
-        let c_str = CString::new(text.clone()).unwrap();
-
-        let input = c_str.as_ptr();
-        let mut opts = opts;
-
-        if opts.tokens == 0 {
-            opts.tokens = 99999999;
-        }
-        
-        if let Some(callback) = opts.token_callback {
-            set_callback(self.state, Some(callback));
-        }
-
-        let reverse_count = opts.stop_prompts.len();
+        self.predict_inner(text, opts, None)
+    }
 
-        let mut c_strings: Vec<CString> = Vec::new();
+    pub fn predict_with_callback(
+        &self,
+        text: String,
+        opts: PredictOptions,
+        callback: TokenCallback,
+    ) -> Result<String, Box<dyn Error>> {
+        self.predict_inner(text, opts, Some(callback))
+    }
 
-        let mut reverse_prompt = Vec::with_capacity(reverse_count);
+    fn predict_inner(
+        &self,
+        text: String,
+        opts: PredictOptions,
+        callback: Option<TokenCallback>,
+    ) -> Result<String, Box<dyn Error>> {
+        let generation = self.generate_inner(text, opts, callback)?;
+
+        Ok(generation
+            .completions
+            .into_iter()
+            .next()
+            .map(|completion| completion.text)
+            .unwrap_or_default())
+    }
 
-        let mut pass: *mut *const c_char = std::ptr::null_mut();
+    pub(crate) fn predict_sampling(
+        &self,
+        text: String,
+        opts: PredictOptions,
+        callback: Option<TokenCallback>,
+    ) -> Result<Generation, Box<dyn Error>> {
+        // llama_predict only checks for cancellation through the token callback
+        let callback = match (&opts.cancel, callback) {
+            (Some(cancel), Some(callback)) => Some(cancel.wrap(callback)),
+            (Some(cancel), None) => Some(cancel.callback()),
+            (None, callback) => callback,
+        };
 
-        for prompt in &opts.stop_prompts {
-            let c_string = CString::new(prompt.clone()).unwrap();
-            reverse_prompt.push(c_string.as_ptr());
-            c_strings.push(c_string);
-        }
+        let params = Params::new(&text, &opts)?;
 
-        if !reverse_prompt.is_empty() {
-            pass = reverse_prompt.as_mut_ptr();
-        }
+        // the callback for this call stands in for the one from set_token_callback
+        let previous = callback.map(|callback| set_callback(self.state, Some(callback)));
 
         let mut out: *mut c_char = std::ptr::null_mut();
 
-        let logit_bias_cstr = CString::new(opts.logit_bias.clone()).unwrap();
-
-        let logit_bias = logit_bias_cstr.as_ptr();
+        let mut stop_reason = 0;
 
-        let path_prompt_cache_cstr = CString::new(opts.path_prompt_cache.clone()).unwrap();
-
-        let path_prompt_cache = path_prompt_cache_cstr.as_ptr();
-
-        let main_gpu_cstr = CString::new(opts.main_gpu.clone()).unwrap();
-
-        let main_gpu = main_gpu_cstr.as_ptr();
-
-        let tensor_split_cstr = CString::new(opts.tensor_split.clone()).unwrap();
-
-        let tensor_split = tensor_split_cstr.as_ptr();
+        let mut stats = llama_binding_predict_stats::default();
 
         unsafe {
-            let params = llama_allocate_params(
-                input,
-                opts.seed,
-                opts.threads,
-                opts.tokens,
-                opts.top_k,
-                opts.top_p,
-                opts.temperature,
-                opts.penalty,
-                opts.repeat,
-                opts.ignore_eos,
-                opts.f16_kv,
-                opts.batch,
-                opts.n_keep,
-                pass,
-                reverse_count as i32,
-                opts.tail_free_sampling_z,
-                opts.typical_p,
-                opts.frequency_penalty,
-                opts.presence_penalty,
-                opts.mirostat,
-                opts.mirostat_eta,
-                opts.mirostat_tau,
-                opts.penalize_nl,
-                logit_bias,
-                path_prompt_cache,
-                opts.prompt_cache_all,
-                opts.m_lock,
-                opts.m_map,
-                main_gpu,
-                tensor_split,
-                opts.prompt_cache_ro,
+            let ret = llama_predict(
+                params.0,
+                self.state,
+                &mut out as _,
+                &mut stop_reason,
+                &mut stats,
             );
 
-            let ret = llama_predict(params, self.state, &mut out as _, opts.debug_mode);
+            if let Some(previous) = previous {
+                set_callback(self.state, previous);
+            }
 
             if ret != 0 {
                 return Err("Failed to predict".into());
             }
 
-            llama_free_params(params);
+            metrics::record_predict(
+                &self.name,
+                &stats,
+                llama_binding_n_ctx(self.state),
+                !opts.path_prompt_cache.is_empty(),
+            );
+
+            let mut res = CStr::from_ptr(out).to_string_lossy().into_owned();
 
-            let c_str: &CStr = CStr::from_ptr(out);
-            let mut res: String = c_str.to_str().unwrap().to_owned();
+            llama_binding_free_result(out);
 
             res = res.trim_start().to_string();
             res = res.trim_start_matches(&text).to_string();
@@ -493,27 +498,40 @@ impl LLama {
                 res = res.trim_end_matches(s).to_string();
             }
 
-            Ok(res)
+            Ok(Generation {
+                completions: vec![Completion {
+                    text: res,
+                    score: None,
+                    stop_reason: StopReason::from_binding(stop_reason),
+                }],
+                stats: GenerationStats {
+                    prompt_tokens: stats.n_prompt_tokens.max(0) as usize,
+                    generated_tokens: stats.n_generated.max(0) as usize,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
         }
     }
 }
 
+// frees the context and the model, dropping is the only way to free them so it happens once
 impl Drop for LLama {
     fn drop(&mut self) {
-        self.free_model();
+        unsafe {
+            llama_binding_free_model(self.state);
+        }
     }
 }
 
-fn set_callback(
-    state: *mut c_void,
-    callback: Option<Box<dyn Fn(String) -> bool + Send + 'static>>,
-) {
+// returns the callback that was set before
+fn set_callback(state: *mut c_void, callback: Option<TokenCallback>) -> Option<TokenCallback> {
     let mut callbacks = CALLBACKS.lock().unwrap();
 
     if let Some(callback) = callback {
-        callbacks.insert(state as usize, callback);
+        callbacks.insert(state as usize, callback)
     } else {
-        callbacks.remove(&(state as usize));
+        callbacks.remove(&(state as usize))
     }
 }
 
@@ -531,3 +549,11 @@ extern "C" fn tokenCallback(state: *mut c_void, token: *const c_char) -> bool {
 
     true
 }
+
+// the tests that need a model are ignored, run them with LLAMA_TEST_MODEL set and --ignored
+#[cfg(test)]
+pub(crate) fn test_model() -> LLama {
+    let path = std::env::var("LLAMA_TEST_MODEL").expect("LLAMA_TEST_MODEL is not set");
+
+    LLama::new(path, &ModelOptions::default()).unwrap()
+}
@@ -0,0 +1,94 @@
+use std::{
+    error::Error,
+    ffi::c_void,
+    fmt::{self, Display},
+};
+
+use crate::{gguf::GgufFile, options::LoadProgressCallback};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoadProgress {
+    pub fraction: f32,
+    pub bytes_read: u64,
+    pub total_bytes: u64,
+    // the tensor being read, when the model's tensor layout could be read up front
+    pub tensor: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadCancelled;
+
+impl Display for LoadCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Model loading was cancelled")
+    }
+}
+
+impl Error for LoadCancelled {}
+
+pub(crate) struct LoadState<'a> {
+    callback: &'a LoadProgressCallback,
+    total_bytes: u64,
+    // tensor data offsets in file order
+    tensors: Vec<(u64, String)>,
+    pub(crate) cancelled: bool,
+}
+
+impl<'a> LoadState<'a> {
+    // llama.cpp only reports the fraction of tensor data read, the GGUF header is read up front
+    // to turn that into bytes and tensor names
+    pub(crate) fn new(path: &str, callback: &'a LoadProgressCallback) -> Self {
+        let mut state = Self {
+            callback,
+            total_bytes: 0,
+            tensors: vec![],
+            cancelled: false,
+        };
+
+        let Ok(gguf) = GgufFile::open(path) else {
+            return state;
+        };
+
+        if let Ok(metadata) = std::fs::metadata(path) {
+            state.total_bytes = metadata.len().saturating_sub(gguf.data_offset);
+        }
+
+        state.tensors = gguf
+            .tensors
+            .into_iter()
+            .map(|tensor| (tensor.offset, tensor.name))
+            .collect();
+        state.tensors.sort();
+
+        state
+    }
+
+    fn report(&mut self, fraction: f32) -> bool {
+        let bytes_read = (self.total_bytes as f64 * fraction as f64) as u64;
+
+        // the last tensor starting at or before the bytes read so far
+        let n_started = self
+            .tensors
+            .partition_point(|(offset, _)| *offset <= bytes_read);
+
+        let progress = LoadProgress {
+            fraction,
+            bytes_read,
+            total_bytes: self.total_bytes,
+            tensor: n_started.checked_sub(1).map(|i| self.tensors[i].1.clone()),
+        };
+
+        if !(self.callback.0)(&progress) {
+            self.cancelled = true;
+        }
+
+        !self.cancelled
+    }
+}
+
+#[no_mangle]
+extern "C" fn loadProgressCallback(progress: f32, state: *mut c_void) -> bool {
+    let state = unsafe { &mut *(state as *mut LoadState) };
+
+    state.report(progress)
+}
@@ -0,0 +1,62 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    ffi::{c_char, c_int, CStr},
+};
+
+use log::Level;
+
+use crate::{
+    LLAMA_BINDING_LOG_DEBUG, LLAMA_BINDING_LOG_ERROR, LLAMA_BINDING_LOG_INFO,
+    LLAMA_BINDING_LOG_WARN,
+};
+
+thread_local! {
+    // llama.cpp logs some lines in pieces, e.g. the dots while loading, they are only logged once
+    // the line is complete. Kept per thread and target so concurrent output isn't spliced together
+    static PENDING: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+fn to_level(level: c_int) -> Level {
+    match level as u32 {
+        LLAMA_BINDING_LOG_ERROR => Level::Error,
+        LLAMA_BINDING_LOG_WARN => Level::Warn,
+        LLAMA_BINDING_LOG_INFO => Level::Info,
+        LLAMA_BINDING_LOG_DEBUG => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+// llama.cpp messages are logged with the llama_cpp target, the binding's own messages with
+// llama_cpp_rs::binding. Only what goes through llama_log_set ends up here, ggml and the backends
+// print to stderr directly
+#[no_mangle]
+extern "C" fn logCallback(level: c_int, target: *const c_char, text: *const c_char) {
+    let level = to_level(level);
+    let target = unsafe { CStr::from_ptr(target) }.to_string_lossy();
+    let text = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+
+    let lines: Vec<String> = PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let pending = pending.entry(target.to_string()).or_default();
+
+        pending.push_str(&text);
+
+        let mut lines = Vec::new();
+
+        while let Some(end) = pending.find('\n') {
+            lines.push(pending.drain(..=end).collect());
+        }
+
+        lines
+    });
+
+    // logged once the buffer is released, in case the logger ends up back in llama.cpp
+    for line in lines {
+        let line = line.trim();
+
+        if !line.is_empty() {
+            log::log!(target: &target, level, "{}", line);
+        }
+    }
+}
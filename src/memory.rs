@@ -0,0 +1,71 @@
+use std::{
+    error::Error,
+    ffi::{c_char, c_int, c_uint, CString},
+    fs::File,
+    io::Read,
+    os::fd::{AsRawFd, FromRawFd},
+};
+
+use crate::{options::ModelOptions, LLama};
+
+const MFD_CLOEXEC: c_uint = 1;
+
+extern "C" {
+    fn memfd_create(name: *const c_char, flags: c_uint) -> c_int;
+}
+
+// only on Linux, which has memfd_create, a temporary file elsewhere would put the weights on disk
+impl LLama {
+    // `name` replaces the file name, e.g. as the model label for metrics. The whole model is
+    // copied, so until `data` is dropped it is held twice, three times without mmap where
+    // llama.cpp reads the copy into its own buffers
+    pub fn from_bytes(
+        name: &str,
+        data: &[u8],
+        opts: &ModelOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut data = data;
+
+        Self::from_reader(name, &mut data, opts)
+    }
+
+    // llama.cpp only loads models from a path, the model is copied into an anonymous in-memory
+    // file which is then loaded through its /proc path, so it never touches the disk. The copy
+    // stays in memory for as long as the model is loaded with mmap, without it llama.cpp reads it
+    // into its own buffers and both are held while loading. The reader is only read front to
+    // back, so it doesn't need to be Seek, any Read + Seek source works as is
+    pub fn from_reader<R: Read>(
+        name: &str,
+        reader: &mut R,
+        opts: &ModelOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let (mut file, path) = memory_file(name)?;
+
+        std::io::copy(reader, &mut file)?;
+
+        // with mmap the mapping outlives the file, without it the weights have been read by now
+        let mut llama = Self::new(path, opts)?;
+        llama.name = name.to_string();
+
+        Ok(llama)
+    }
+}
+
+fn memory_file(name: &str) -> Result<(File, String), Box<dyn Error>> {
+    let name = CString::new(name)?;
+
+    let fd = unsafe { memfd_create(name.as_ptr(), MFD_CLOEXEC) };
+
+    if fd < 0 {
+        return Err(format!(
+            "Failed to create an in-memory file: {}",
+            std::io::Error::last_os_error()
+        )
+        .into());
+    }
+
+    let file = unsafe { File::from_raw_fd(fd) };
+    let path = format!("/proc/self/fd/{}", file.as_raw_fd());
+
+    Ok((file, path))
+}
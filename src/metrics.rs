@@ -0,0 +1,116 @@
+// cumulative metrics recorded through the metrics crate facade when the metrics feature is
+// enabled, every metric has a model label with the model's file name. Installing a recorder or
+// exporter is left to the application
+use crate::{generation::GenerationStats, llama_binding_predict_stats, timings::Timings};
+
+pub const PROMPT_TOKENS: &str = "llama_prompt_tokens_total";
+pub const GENERATED_TOKENS: &str = "llama_generated_tokens_total";
+// tokens per second from llama.cpp's timings, one sample per generation. llama.cpp counts
+// every batch of more than one token as prompt eval, which with n_completions, beam search and
+// drafted decoding includes most of the generated tokens. Not recorded for the scheduler
+pub const PROMPT_EVAL_THROUGHPUT: &str = "llama_prompt_eval_tokens_per_second";
+pub const GENERATION_THROUGHPUT: &str = "llama_generation_tokens_per_second";
+// fraction of the context in use after the last predict call, this and the context swap and
+// session cache metrics are only recorded for plain sampling
+pub const KV_CACHE_UTILIZATION: &str = "llama_kv_cache_utilization";
+pub const CONTEXT_SWAPS: &str = "llama_context_swaps_total";
+pub const SESSION_CACHE_HITS: &str = "llama_session_cache_hits_total";
+pub const SESSION_CACHE_MISSES: &str = "llama_session_cache_misses_total";
+pub const EMBEDDINGS: &str = "llama_embeddings_total";
+// with a kind label, see ErrorKind
+pub const ERRORS: &str = "llama_errors_total";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    InvalidOptions,
+    Predict,
+    Embeddings,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::InvalidOptions => "invalid_options",
+            ErrorKind::Predict => "predict",
+            ErrorKind::Embeddings => "embeddings",
+        }
+    }
+}
+
+// called once per generation, whichever way it was decoded
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_generation(model: &str, stats: &GenerationStats, timings: &Timings) {
+    record_tokens(model, stats.prompt_tokens, stats.generated_tokens);
+
+    #[cfg(feature = "metrics")]
+    {
+        let labels = [("model", model.to_string())];
+
+        // calls that didn't get to a phase, e.g. a prompt fully restored from the session file,
+        // are left out instead of recorded as 0 tokens/s
+        if timings.n_prompt_eval > 0 {
+            ::metrics::histogram!(PROMPT_EVAL_THROUGHPUT, &labels)
+                .record(timings.prompt_eval_tokens_per_second());
+        }
+
+        if timings.n_eval > 0 {
+            ::metrics::histogram!(GENERATION_THROUGHPUT, &labels)
+                .record(timings.eval_tokens_per_second());
+        }
+    }
+}
+
+// the scheduler only records token counts, its requests share the context and its timings
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_tokens(model: &str, prompt_tokens: usize, generated_tokens: usize) {
+    #[cfg(feature = "metrics")]
+    {
+        let labels = [("model", model.to_string())];
+
+        ::metrics::counter!(PROMPT_TOKENS, &labels).increment(prompt_tokens as u64);
+        ::metrics::counter!(GENERATED_TOKENS, &labels).increment(generated_tokens as u64);
+    }
+}
+
+// what only llama_predict reports, the other paths manage the KV cache themselves
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_predict(
+    model: &str,
+    stats: &llama_binding_predict_stats,
+    n_ctx: i32,
+    session_cache: bool,
+) {
+    #[cfg(feature = "metrics")]
+    {
+        let labels = [("model", model.to_string())];
+
+        ::metrics::counter!(CONTEXT_SWAPS, &labels).increment(stats.n_context_swaps as u64);
+
+        if n_ctx > 0 {
+            ::metrics::gauge!(KV_CACHE_UTILIZATION, &labels)
+                .set(stats.n_past as f64 / n_ctx as f64);
+        }
+
+        if session_cache {
+            let name = if stats.n_session_tokens > 0 {
+                SESSION_CACHE_HITS
+            } else {
+                SESSION_CACHE_MISSES
+            };
+
+            ::metrics::counter!(name, &labels).increment(1);
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_embeddings(model: &str) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(EMBEDDINGS, "model" => model.to_string()).increment(1);
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn record_error(model: &str, kind: ErrorKind) {
+    #[cfg(feature = "metrics")]
+    ::metrics::counter!(ERRORS, "model" => model.to_string(), "kind" => kind.as_str()).increment(1);
+}
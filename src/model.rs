@@ -0,0 +1,154 @@
+use std::ffi::{c_char, CString};
+
+use crate::{
+    llama_binding_model_desc, llama_binding_model_meta, llama_binding_model_n_params,
+    llama_binding_model_size, llama_binding_n_ctx_train, llama_binding_n_embd,
+    llama_binding_n_vocab, llama_binding_rope_freq_scale_train, llama_binding_token_bos,
+    llama_binding_token_eos, llama_binding_token_nl, llama_binding_vocab_type, LLama,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VocabType {
+    SentencePiece,
+    BytePair,
+    Other(i32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub description: String,
+    pub architecture: Option<String>,
+    pub n_vocab: usize,
+    pub n_embd: usize,
+    pub n_layer: Option<usize>,
+    pub n_ctx_train: usize,
+    pub n_params: u64,
+    pub size: u64,
+    pub rope_freq_base: Option<f32>,
+    pub rope_freq_scale: f32,
+    pub vocab_type: VocabType,
+    pub token_bos: i32,
+    pub token_eos: i32,
+    pub token_nl: i32,
+}
+
+impl LLama {
+    pub fn info(&self) -> ModelInfo {
+        ModelInfo {
+            description: self.description(),
+            architecture: self.architecture(),
+            n_vocab: self.n_vocab(),
+            n_embd: self.n_embd(),
+            n_layer: self.n_layer(),
+            n_ctx_train: self.n_ctx_train(),
+            n_params: self.n_params(),
+            size: self.size(),
+            rope_freq_base: self.rope_freq_base(),
+            rope_freq_scale: self.rope_freq_scale(),
+            vocab_type: self.vocab_type(),
+            token_bos: self.token_bos(),
+            token_eos: self.token_eos(),
+            token_nl: self.token_nl(),
+        }
+    }
+
+    pub fn n_vocab(&self) -> usize {
+        unsafe { llama_binding_n_vocab(self.state) as usize }
+    }
+
+    pub fn n_embd(&self) -> usize {
+        unsafe { llama_binding_n_embd(self.state) as usize }
+    }
+
+    pub fn n_layer(&self) -> Option<usize> {
+        self.architecture_meta("block_count")?.parse().ok()
+    }
+
+    // the context length the model was trained with, as opposed to the context it was loaded with
+    pub fn n_ctx_train(&self) -> usize {
+        unsafe { llama_binding_n_ctx_train(self.state) as usize }
+    }
+
+    pub fn n_params(&self) -> u64 {
+        unsafe { llama_binding_model_n_params(self.state) }
+    }
+
+    // size of the weights in bytes
+    pub fn size(&self) -> u64 {
+        unsafe { llama_binding_model_size(self.state) }
+    }
+
+    pub fn description(&self) -> String {
+        read_string(|buf, size| unsafe { llama_binding_model_desc(self.state, buf, size) })
+            .unwrap_or_default()
+    }
+
+    pub fn architecture(&self) -> Option<String> {
+        self.meta("general.architecture")
+    }
+
+    pub fn rope_freq_base(&self) -> Option<f32> {
+        self.architecture_meta("rope.freq_base")?.parse().ok()
+    }
+
+    pub fn rope_freq_scale(&self) -> f32 {
+        unsafe { llama_binding_rope_freq_scale_train(self.state) }
+    }
+
+    pub fn vocab_type(&self) -> VocabType {
+        match unsafe { llama_binding_vocab_type(self.state) } {
+            0 => VocabType::SentencePiece,
+            1 => VocabType::BytePair,
+            other => VocabType::Other(other),
+        }
+    }
+
+    pub fn token_bos(&self) -> i32 {
+        unsafe { llama_binding_token_bos(self.state) }
+    }
+
+    pub fn token_eos(&self) -> i32 {
+        unsafe { llama_binding_token_eos(self.state) }
+    }
+
+    pub fn token_nl(&self) -> i32 {
+        unsafe { llama_binding_token_nl(self.state) }
+    }
+
+    // a GGUF metadata value of the loaded model, formatted as a string
+    pub fn meta(&self, key: &str) -> Option<String> {
+        let key = CString::new(key).ok()?;
+
+        read_string(|buf, size| unsafe {
+            llama_binding_model_meta(self.state, key.as_ptr(), buf, size)
+        })
+    }
+
+    fn architecture_meta(&self, key: &str) -> Option<String> {
+        self.meta(&format!("{}.{}", self.architecture()?, key))
+    }
+}
+
+// the binding functions follow snprintf, returning the full length even when the buffer is too
+// small, or a negative value when there is nothing to read
+fn read_string<F>(read: F) -> Option<String>
+where
+    F: Fn(*mut c_char, i32) -> i32,
+{
+    let mut buf: Vec<u8> = vec![0; 128];
+
+    let mut n = read(buf.as_mut_ptr() as *mut c_char, buf.len() as i32);
+
+    if n < 0 {
+        return None;
+    }
+
+    if n as usize >= buf.len() {
+        buf.resize(n as usize + 1, 0);
+        n = read(buf.as_mut_ptr() as *mut c_char, buf.len() as i32);
+    }
+
+    buf.truncate(n.max(0) as usize);
+
+    Some(String::from_utf8_lossy(&buf).into_owned())
+}
@@ -1,4 +1,100 @@
+use std::{fmt, str::FromStr, sync::Arc};
+
+use crate::{cancel::CancelToken, load::LoadProgress};
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoraAdapter {
+    pub path: String,
+    pub scale: f32,
+    // a higher precision model to apply the adapter against when the loaded model is quantized
+    pub base_model: Option<String>,
+}
+
+impl LoraAdapter {
+    pub fn new(path: String, scale: f32) -> Self {
+        Self {
+            path,
+            scale,
+            base_model: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum GpuLayers {
+    #[default]
+    None,
+    N(u32),
+    All,
+}
+
+impl GpuLayers {
+    // llama.cpp offloads everything, including the output layer, when asked for more layers than
+    // the model has
+    pub(crate) fn count(&self) -> i32 {
+        match *self {
+            GpuLayers::None => 0,
+            GpuLayers::N(n) => saturating_i32(n),
+            GpuLayers::All => 999,
+        }
+    }
+}
+
+// the bundled llama.cpp always splits tensors by rows across the devices in tensor_split,
+// splitting by layers needs a newer version
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SplitMode {
+    // everything is kept on main_gpu
+    None,
+    #[default]
+    Row,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct GpuConfig {
+    pub main_gpu: usize,
+    // proportion of the model per device, empty lets llama.cpp decide
+    pub tensor_split: Vec<f32>,
+    pub split_mode: SplitMode,
+    pub n_gpu_layers: GpuLayers,
+}
+
+impl GpuConfig {
+    pub(crate) fn is_default(&self) -> bool {
+        *self == GpuConfig::default()
+    }
+
+    pub(crate) fn effective_tensor_split(&self) -> Vec<f32> {
+        match self.split_mode {
+            SplitMode::None => {
+                let mut split = vec![0.0; self.main_gpu + 1];
+                split[self.main_gpu] = 1.0;
+                split
+            }
+            SplitMode::Row => self.tensor_split.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LoadProgressCallback(pub Arc<dyn Fn(&LoadProgress) -> bool + Send + Sync + 'static>);
+
+impl fmt::Debug for LoadProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("LoadProgressCallback")
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct ModelOptions {
     pub context_size: i32,
     pub seed: i32,
@@ -9,10 +105,12 @@ pub struct ModelOptions {
     pub low_vram: bool,
     pub vocab_only: bool,
     pub embeddings: bool,
-    pub n_gpu_layers: i32,
-    pub main_gpu: String,
-    pub tensor_split: String,
+    pub gpu: GpuConfig,
     pub numa: bool,
+    pub lora_adapters: Vec<LoraAdapter>,
+    // returning false cancels loading, LLama::new then fails with LoadCancelled
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub progress_callback: Option<LoadProgressCallback>,
 }
 
 impl Default for ModelOptions {
@@ -28,17 +126,80 @@ impl Default for ModelOptions {
             m_map: true,
             n_batch: 0,
             numa: false,
-            n_gpu_layers: 0,
-            main_gpu: String::from(""),
-            tensor_split: String::from(""),
+            gpu: GpuConfig::default(),
+            lora_adapters: vec![],
+            progress_callback: None,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum GenerationMode {
+    #[default]
+    Sampling,
+    BeamSearch {
+        beams: usize,
+        length_penalty: f32,
+        early_stopping: bool,
+    },
+}
+
+// drafts continuations by matching the last n-gram of the sequence against earlier tokens,
+// mostly the prompt, and verifies them in one batch
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
+pub struct PromptLookup {
+    pub n_draft: usize,
+    pub ngram_min: usize,
+    pub ngram_max: usize,
+}
+
+impl Default for PromptLookup {
+    fn default() -> Self {
+        Self {
+            n_draft: 10,
+            ngram_min: 1,
+            ngram_max: 3,
+        }
+    }
+}
+
+// called with every generated piece, returning false stops generation
+pub type TokenCallback = Box<dyn Fn(String) -> bool + Send + 'static>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Preset {
+    Precise,
+    Creative,
+    Deterministic,
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "precise" => Ok(Preset::Precise),
+            "creative" => Ok(Preset::Creative),
+            "deterministic" => Ok(Preset::Deterministic),
+            _ => Err(format!("Unknown preset {:?}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(default))]
 pub struct PredictOptions {
     pub seed: i32,
     pub threads: i32,
-    pub tokens: i32,
+    // None generates until the end of text or a stop prompt
+    pub max_tokens: Option<u32>,
     pub top_k: i32,
     pub repeat: i32,
     pub batch: i32,
@@ -60,15 +221,17 @@ pub struct PredictOptions {
     pub mirostat_tau: f32,
     pub penalize_nl: bool,
     pub logit_bias: String,
-    pub token_callback: Option<Box<dyn Fn(String) -> bool + Send + 'static>>,
-    // pub token_callback: Option<fn(String) -> bool>,
     pub path_prompt_cache: String,
     pub m_lock: bool,
     pub m_map: bool,
     pub prompt_cache_all: bool,
     pub prompt_cache_ro: bool,
-    pub main_gpu: String,
-    pub tensor_split: String,
+    pub mode: GenerationMode,
+    pub n_completions: usize,
+    pub prompt_lookup: Option<PromptLookup>,
+    // stops generation once cancelled, unlike a token callback in every generation mode
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub cancel: Option<CancelToken>,
 }
 
 impl Default for PredictOptions {
@@ -76,7 +239,7 @@ impl Default for PredictOptions {
         Self {
             seed: -1,
             threads: 8,
-            tokens: 128,
+            max_tokens: Some(128),
             top_k: 40,
             repeat: 64,
             batch: 512,
@@ -97,188 +260,677 @@ impl Default for PredictOptions {
             mirostat_tau: 5.0,
             penalize_nl: false,
             logit_bias: String::from(""),
-            token_callback: None,
             path_prompt_cache: String::from(""),
             m_lock: false,
             m_map: false,
             prompt_cache_all: false,
             prompt_cache_ro: false,
-            main_gpu: String::from(""),
-            tensor_split: String::from(""),
+            mode: GenerationMode::Sampling,
+            n_completions: 1,
+            prompt_lookup: None,
+            cancel: None,
         }
     }
 }
 
+// the llama_ftype values llama.cpp can quantize to
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuantizeType {
+    F32 = 0,
+    F16 = 1,
+    Q4_0 = 2,
+    Q4_1 = 3,
+    Q8_0 = 7,
+    Q5_0 = 8,
+    Q5_1 = 9,
+    Q2_K = 10,
+    Q3_K_S = 11,
+    Q3_K_M = 12,
+    Q3_K_L = 13,
+    Q4_K_S = 14,
+    Q4_K_M = 15,
+    Q5_K_S = 16,
+    Q5_K_M = 17,
+    Q6_K = 18,
+}
+
+// there is no `pure` option to quantize every tensor to ftype, the pinned llama.cpp's
+// llama_model_quantize_params has no such field and always keeps some tensors at a higher precision
+pub struct QuantizeOptions {
+    pub ftype: QuantizeType,
+    // 0 uses every available core
+    pub threads: i32,
+    pub allow_requantize: bool,
+    pub quantize_output_tensor: bool,
+    // copies the tensors as they are, only rewriting the file
+    pub only_copy: bool,
+    // called with the index of the tensor being quantized and the total number of tensors
+    pub progress_callback: Option<Box<dyn Fn(usize, usize) + Send + 'static>>,
+}
+
+impl Default for QuantizeOptions {
+    fn default() -> Self {
+        Self {
+            ftype: QuantizeType::Q5_1,
+            threads: 0,
+            allow_requantize: false,
+            quantize_output_tensor: true,
+            only_copy: false,
+            progress_callback: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ModelOptionsBuilder {
+    opts: ModelOptions,
+}
+
 impl ModelOptions {
-    pub fn set_context(&mut self, context_size: i32) {
-        self.context_size = context_size;
+    pub fn builder() -> ModelOptionsBuilder {
+        ModelOptionsBuilder::default()
     }
+}
 
-    pub fn set_model_seed(&mut self, seed: i32) {
-        self.seed = seed;
+// counts are unsigned here so they can't be negative, anything else is checked by build()
+impl ModelOptionsBuilder {
+    // 0 takes the context size the model was trained with
+    pub fn context(mut self, context_size: u32) -> Self {
+        self.opts.context_size = saturating_i32(context_size);
+        self
     }
 
-    pub fn enable_f16_memory(&mut self) {
-        self.f16_memory = true;
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.opts.seed = saturating_i32(seed);
+        self
     }
 
-    pub fn enable_embeddings(&mut self) {
-        self.embeddings = true;
+    pub fn n_batch(mut self, n_batch: u32) -> Self {
+        self.opts.n_batch = saturating_i32(n_batch);
+        self
     }
 
-    pub fn enable_m_lock(&mut self) {
-        self.m_lock = true;
+    pub fn f16_memory(mut self, f16_memory: bool) -> Self {
+        self.opts.f16_memory = f16_memory;
+        self
     }
 
-    pub fn set_m_map(&mut self, m_map: bool) {
-        self.m_map = m_map;
+    pub fn m_lock(mut self, m_lock: bool) -> Self {
+        self.opts.m_lock = m_lock;
+        self
     }
 
-    pub fn set_n_batch(&mut self, n_batch: i32) {
-        self.n_batch = n_batch;
+    pub fn m_map(mut self, m_map: bool) -> Self {
+        self.opts.m_map = m_map;
+        self
     }
 
-    pub fn set_tensor_split(&mut self, tensor_split: String) {
-        self.tensor_split = tensor_split;
+    pub fn low_vram(mut self, low_vram: bool) -> Self {
+        self.opts.low_vram = low_vram;
+        self
     }
 
-    pub fn set_gpu_layers(&mut self, n_gpu_layers: i32) {
-        self.n_gpu_layers = n_gpu_layers;
+    pub fn vocab_only(mut self, vocab_only: bool) -> Self {
+        self.opts.vocab_only = vocab_only;
+        self
     }
 
-    pub fn set_main_gpu(&mut self, main_gpu: String) {
-        self.main_gpu = main_gpu;
+    pub fn embeddings(mut self, embeddings: bool) -> Self {
+        self.opts.embeddings = embeddings;
+        self
+    }
+
+    pub fn numa(mut self, numa: bool) -> Self {
+        self.opts.numa = numa;
+        self
+    }
+
+    pub fn gpu(mut self, gpu: GpuConfig) -> Self {
+        self.opts.gpu = gpu;
+        self
+    }
+
+    pub fn gpu_layers(mut self, n_gpu_layers: GpuLayers) -> Self {
+        self.opts.gpu.n_gpu_layers = n_gpu_layers;
+        self
+    }
+
+    pub fn main_gpu(mut self, main_gpu: usize) -> Self {
+        self.opts.gpu.main_gpu = main_gpu;
+        self
+    }
+
+    pub fn tensor_split(mut self, tensor_split: Vec<f32>) -> Self {
+        self.opts.gpu.tensor_split = tensor_split;
+        self
+    }
+
+    pub fn split_mode(mut self, split_mode: SplitMode) -> Self {
+        self.opts.gpu.split_mode = split_mode;
+        self
+    }
+
+    pub fn lora_adapter(mut self, adapter: LoraAdapter) -> Self {
+        self.opts.lora_adapters.push(adapter);
+        self
+    }
+
+    pub fn progress_callback<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&LoadProgress) -> bool + Send + Sync + 'static,
+    {
+        self.opts.progress_callback = Some(LoadProgressCallback(Arc::new(callback)));
+        self
+    }
+
+    pub fn build(self) -> Result<ModelOptions, OptionsError> {
+        self.opts.validate()?;
+
+        Ok(self.opts)
     }
 }
 
 impl PredictOptions {
-    pub fn set_prediction_tensor_split(&mut self, tensor_split: String) {
-        self.tensor_split = tensor_split;
+    pub fn preset(preset: Preset) -> Self {
+        match preset {
+            // focused and repeatable, for extraction and question answering
+            Preset::Precise => Self {
+                temperature: 0.7,
+                top_k: 40,
+                top_p: 0.1,
+                penalty: 1.18,
+                ..Default::default()
+            },
+            Preset::Creative => Self {
+                temperature: 1.1,
+                top_k: 100,
+                top_p: 0.98,
+                penalty: 1.1,
+                ..Default::default()
+            },
+            // greedy decoding, the same prompt always gives the same output. A seed of 0 or less
+            // is replaced with the time, the fixed one keeps anything that still samples repeatable
+            Preset::Deterministic => Self {
+                seed: 42,
+                temperature: 0.0,
+                top_k: 1,
+                penalty: 1.0,
+                ..Default::default()
+            },
+        }
     }
 
-    pub fn set_prediction_main_gpu(&mut self, main_gpu: String) {
-        self.main_gpu = main_gpu;
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
     }
 
-    pub fn enable_f16_kv(&mut self) {
-        self.f16_kv = true;
+    // the n_predict value llama.cpp expects, where -1 means no limit
+    pub(crate) fn n_predict(&self) -> i32 {
+        match self.max_tokens {
+            Some(max_tokens) => saturating_i32(max_tokens),
+            None => -1,
+        }
     }
 
-    pub fn enable_debug_mode(&mut self) {
-        self.debug_mode = true;
+    pub fn builder() -> PredictOptionsBuilder {
+        PredictOptionsBuilder::default()
     }
+}
+
+// starts from the defaults, or from a preset with PredictOptionsBuilder::from(PredictOptions::preset(..))
+#[derive(Debug, Clone, Default)]
+pub struct PredictOptionsBuilder {
+    opts: PredictOptions,
+}
+
+impl From<PredictOptions> for PredictOptionsBuilder {
+    fn from(opts: PredictOptions) -> Self {
+        Self { opts }
+    }
+}
 
-    pub fn enable_prompt_cache_all(&mut self) {
-        self.prompt_cache_all = true;
+impl PredictOptionsBuilder {
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.opts.seed = saturating_i32(seed);
+        self
     }
 
-    pub fn enable_prompt_cache_ro(&mut self) {
-        self.prompt_cache_ro = true;
+    pub fn threads(mut self, threads: u32) -> Self {
+        self.opts.threads = saturating_i32(threads);
+        self
     }
 
-    pub fn enable_m_lock(&mut self) {
-        self.m_lock = true;
+    // takes a count or None for no limit
+    pub fn max_tokens(mut self, max_tokens: impl Into<Option<u32>>) -> Self {
+        self.opts.max_tokens = max_tokens.into();
+        self
     }
 
-    pub fn set_m_lock(&mut self, m_lock: bool) {
-        self.m_lock = m_lock;
+    pub fn top_k(mut self, top_k: u32) -> Self {
+        self.opts.top_k = saturating_i32(top_k);
+        self
     }
 
-    pub fn set_memory_map(&mut self, m_map: bool) {
-        self.m_map = m_map;
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.opts.top_p = top_p;
+        self
     }
 
-    pub fn set_token_callback(
-        &mut self,
-        token_callback: Option<Box<dyn Fn(String) -> bool + Send + 'static>>,
-    ) {
-        self.token_callback = token_callback;
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.opts.temperature = temperature;
+        self
     }
-    // pub fn set_token_callback(&mut self, token_callback: Option<fn(String) -> bool>) {
-    //     self.token_callback = token_callback;
-    // }
 
-    pub fn set_path_prompt_cache(&mut self, path_prompt_cache: String) {
-        self.path_prompt_cache = path_prompt_cache;
+    pub fn penalty(mut self, penalty: f32) -> Self {
+        self.opts.penalty = penalty;
+        self
     }
 
-    pub fn set_seed(&mut self, seed: i32) {
-        self.seed = seed;
+    pub fn repeat(mut self, repeat: u32) -> Self {
+        self.opts.repeat = saturating_i32(repeat);
+        self
     }
 
-    pub fn set_threads(&mut self, threads: i32) {
-        self.threads = threads;
+    pub fn batch(mut self, batch: u32) -> Self {
+        self.opts.batch = saturating_i32(batch);
+        self
     }
 
-    pub fn set_tokens(&mut self, tokens: i32) {
-        self.tokens = tokens;
+    pub fn n_keep(mut self, n_keep: u32) -> Self {
+        self.opts.n_keep = saturating_i32(n_keep);
+        self
     }
 
-    pub fn set_top_k(&mut self, top_k: i32) {
-        self.top_k = top_k;
+    pub fn f16_kv(mut self, f16_kv: bool) -> Self {
+        self.opts.f16_kv = f16_kv;
+        self
     }
 
-    pub fn set_repeat(&mut self, repeat: i32) {
-        self.repeat = repeat;
+    pub fn debug_mode(mut self, debug_mode: bool) -> Self {
+        self.opts.debug_mode = debug_mode;
+        self
     }
 
-    pub fn set_batch(&mut self, batch: i32) {
-        self.batch = batch;
+    pub fn stop_prompt(mut self, stop_prompt: impl Into<String>) -> Self {
+        self.opts.stop_prompts.push(stop_prompt.into());
+        self
     }
 
-    pub fn set_n_keep(&mut self, n_keep: i32) {
-        self.n_keep = n_keep;
+    pub fn ignore_eos(mut self, ignore_eos: bool) -> Self {
+        self.opts.ignore_eos = ignore_eos;
+        self
     }
 
-    pub fn set_top_p(&mut self, top_p: f32) {
-        self.top_p = top_p;
+    pub fn tail_free_sampling_z(mut self, tail_free_sampling_z: f32) -> Self {
+        self.opts.tail_free_sampling_z = tail_free_sampling_z;
+        self
     }
 
-    pub fn set_temperature(&mut self, temperature: f32) {
-        self.temperature = temperature;
+    pub fn typical_p(mut self, typical_p: f32) -> Self {
+        self.opts.typical_p = typical_p;
+        self
     }
 
-    pub fn set_penalty(&mut self, penalty: f32) {
-        self.penalty = penalty;
+    pub fn frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.opts.frequency_penalty = frequency_penalty;
+        self
     }
 
-    pub fn set_tail_free_sampling_z(&mut self, tail_free_sampling_z: f32) {
-        self.tail_free_sampling_z = tail_free_sampling_z;
+    pub fn presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.opts.presence_penalty = presence_penalty;
+        self
     }
 
-    pub fn set_typical_p(&mut self, typical_p: f32) {
-        self.typical_p = typical_p;
+    pub fn mirostat(mut self, mirostat: u32) -> Self {
+        self.opts.mirostat = saturating_i32(mirostat);
+        self
     }
 
-    pub fn set_frequency_penalty(&mut self, frequency_penalty: f32) {
-        self.frequency_penalty = frequency_penalty;
+    pub fn mirostat_eta(mut self, mirostat_eta: f32) -> Self {
+        self.opts.mirostat_eta = mirostat_eta;
+        self
     }
 
-    pub fn set_presence_penalty(&mut self, presence_penalty: f32) {
-        self.presence_penalty = presence_penalty;
+    pub fn mirostat_tau(mut self, mirostat_tau: f32) -> Self {
+        self.opts.mirostat_tau = mirostat_tau;
+        self
     }
 
-    pub fn set_mirostat(&mut self, mirostat: i32) {
-        self.mirostat = mirostat;
+    pub fn penalize_nl(mut self, penalize_nl: bool) -> Self {
+        self.opts.penalize_nl = penalize_nl;
+        self
+    }
+
+    pub fn logit_bias(mut self, logit_bias: impl Into<String>) -> Self {
+        self.opts.logit_bias = logit_bias.into();
+        self
+    }
+
+    pub fn path_prompt_cache(mut self, path_prompt_cache: impl Into<String>) -> Self {
+        self.opts.path_prompt_cache = path_prompt_cache.into();
+        self
+    }
+
+    pub fn prompt_cache_all(mut self, prompt_cache_all: bool) -> Self {
+        self.opts.prompt_cache_all = prompt_cache_all;
+        self
+    }
+
+    pub fn prompt_cache_ro(mut self, prompt_cache_ro: bool) -> Self {
+        self.opts.prompt_cache_ro = prompt_cache_ro;
+        self
+    }
+
+    pub fn m_lock(mut self, m_lock: bool) -> Self {
+        self.opts.m_lock = m_lock;
+        self
+    }
+
+    pub fn m_map(mut self, m_map: bool) -> Self {
+        self.opts.m_map = m_map;
+        self
+    }
+
+    pub fn mode(mut self, mode: GenerationMode) -> Self {
+        self.opts.mode = mode;
+        self
+    }
+
+    pub fn n_completions(mut self, n_completions: usize) -> Self {
+        self.opts.n_completions = n_completions;
+        self
+    }
+
+    pub fn prompt_lookup(mut self, prompt_lookup: PromptLookup) -> Self {
+        self.opts.prompt_lookup = Some(prompt_lookup);
+        self
+    }
+
+    pub fn cancel(mut self, cancel: CancelToken) -> Self {
+        self.opts.cancel = Some(cancel);
+        self
+    }
+
+    pub fn build(self) -> Result<PredictOptions, OptionsError> {
+        self.opts.validate()?;
+
+        Ok(self.opts)
+    }
+}
+
+fn saturating_i32(value: u32) -> i32 {
+    value.min(i32::MAX as u32) as i32
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OptionsError {
+    InvalidMainGpu { main_gpu: usize, max: usize },
+    InvalidTensorSplit(f32),
+    TooManyTensorSplits { count: usize, max: usize },
+    GpuNotSupported,
+    InvalidThreads(i32),
+    InvalidBatchSize(i32),
+    BatchLargerThanContext { n_batch: i32, n_ctx: i32 },
+    LargerThanBatch { name: &'static str, n_tokens: usize, n_batch: i32 },
+    Negative { name: &'static str, value: i32 },
+    OutOfRange { name: &'static str, value: f32 },
+    InvalidLogitBias(String),
+    LogitBiasTokenOutOfRange { token: i32, n_vocab: usize },
+    InvalidMirostat(i32),
+    ConflictingMirostat(&'static str),
+    NoCompletions,
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OptionsError::InvalidMainGpu { main_gpu, max } => write!(
+                f,
+                "Main GPU {} is out of range, at most {} devices are supported",
+                main_gpu, max
+            ),
+            OptionsError::InvalidTensorSplit(value) => write!(
+                f,
+                "Tensor split proportions must be finite and not negative, got {}",
+                value
+            ),
+            OptionsError::TooManyTensorSplits { count, max } => write!(
+                f,
+                "Tensor split has {} parts but at most {} devices are supported",
+                count, max
+            ),
+            OptionsError::GpuNotSupported => write!(
+                f,
+                "GPU options are set but the crate was built without GPU support"
+            ),
+            OptionsError::InvalidThreads(threads) => {
+                write!(f, "Thread count must be at least 1, got {}", threads)
+            }
+            OptionsError::InvalidBatchSize(n_batch) => {
+                write!(f, "Batch size must be at least 1, got {}", n_batch)
+            }
+            OptionsError::BatchLargerThanContext { n_batch, n_ctx } => write!(
+                f,
+                "Batch size {} is larger than the context size {}",
+                n_batch, n_ctx
+            ),
+            OptionsError::LargerThanBatch {
+                name,
+                n_tokens,
+                n_batch,
+            } => write!(
+                f,
+                "{} needs {} tokens per batch, more than the batch size {}",
+                name, n_tokens, n_batch
+            ),
+            OptionsError::Negative { name, value } => {
+                write!(f, "{} must not be negative, got {}", name, value)
+            }
+            OptionsError::OutOfRange { name, value } => {
+                write!(f, "{} is out of range, got {}", name, value)
+            }
+            OptionsError::InvalidLogitBias(bias) => write!(
+                f,
+                "Invalid logit bias {:?}, expected TOKEN+BIAS or TOKEN-BIAS with a token id that isn't \
+                 negative and a finite bias",
+                bias
+            ),
+            OptionsError::LogitBiasTokenOutOfRange { token, n_vocab } => write!(
+                f,
+                "Logit bias token {} is out of range, the vocabulary has {} tokens",
+                token, n_vocab
+            ),
+            OptionsError::InvalidMirostat(mirostat) => {
+                write!(f, "Mirostat must be 0, 1 or 2, got {}", mirostat)
+            }
+            OptionsError::ConflictingMirostat(reason) => write!(f, "Mirostat {}", reason),
+            OptionsError::NoCompletions => write!(f, "n_completions must be at least 1"),
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+impl ModelOptions {
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if self.context_size < 0 {
+            return Err(OptionsError::Negative {
+                name: "context_size",
+                value: self.context_size,
+            });
+        }
+
+        if self.n_batch < 0 {
+            return Err(OptionsError::Negative {
+                name: "n_batch",
+                value: self.n_batch,
+            });
+        }
+
+        // a context size of 0 is taken from the model, which isn't known yet
+        if self.context_size > 0 && self.n_batch > self.context_size {
+            return Err(OptionsError::BatchLargerThanContext {
+                n_batch: self.n_batch,
+                n_ctx: self.context_size,
+            });
+        }
+
+        self.gpu.validate()
     }
+}
 
-    pub fn set_mirostat_eta(&mut self, mirostat_eta: f32) {
-        self.mirostat_eta = mirostat_eta;
+impl GpuConfig {
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if self.is_default() {
+            return Ok(());
+        }
+
+        if !cfg!(any(feature = "cuda", feature = "metal", feature = "opencl")) {
+            return Err(OptionsError::GpuNotSupported);
+        }
+
+        let max = unsafe { crate::llama_binding_max_devices() as usize };
+
+        if self.main_gpu >= max {
+            return Err(OptionsError::InvalidMainGpu {
+                main_gpu: self.main_gpu,
+                max,
+            });
+        }
+
+        if self.tensor_split.len() > max {
+            return Err(OptionsError::TooManyTensorSplits {
+                count: self.tensor_split.len(),
+                max,
+            });
+        }
+
+        for &value in &self.tensor_split {
+            if !value.is_finite() || value < 0.0 {
+                return Err(OptionsError::InvalidTensorSplit(value));
+            }
+        }
+
+        Ok(())
     }
+}
 
-    pub fn set_mirostat_tau(&mut self, mirostat_tau: f32) {
-        self.mirostat_tau = mirostat_tau;
+impl PredictOptions {
+    pub fn validate(&self) -> Result<(), OptionsError> {
+        if self.threads < 1 {
+            return Err(OptionsError::InvalidThreads(self.threads));
+        }
+
+        if self.batch < 1 {
+            return Err(OptionsError::InvalidBatchSize(self.batch));
+        }
+
+        if self.n_keep < 0 {
+            return Err(OptionsError::Negative {
+                name: "n_keep",
+                value: self.n_keep,
+            });
+        }
+
+        if self.n_completions == 0 {
+            return Err(OptionsError::NoCompletions);
+        }
+
+        let unit_ranges = [
+            ("top_p", self.top_p),
+            ("typical_p", self.typical_p),
+            ("tail_free_sampling_z", self.tail_free_sampling_z),
+        ];
+
+        for (name, value) in unit_ranges {
+            if value.is_nan() || value <= 0.0 || value > 1.0 {
+                return Err(OptionsError::OutOfRange { name, value });
+            }
+        }
+
+        let non_negative = [
+            ("temperature", self.temperature),
+            ("penalty", self.penalty),
+            ("frequency_penalty", self.frequency_penalty),
+            ("presence_penalty", self.presence_penalty),
+        ];
+
+        for (name, value) in non_negative {
+            if !value.is_finite() || value < 0.0 {
+                return Err(OptionsError::OutOfRange { name, value });
+            }
+        }
+
+        match self.mirostat {
+            0 => {}
+            1 | 2 => {
+                if self.mirostat_tau.is_nan() || self.mirostat_tau <= 0.0 {
+                    return Err(OptionsError::OutOfRange {
+                        name: "mirostat_tau",
+                        value: self.mirostat_tau,
+                    });
+                }
+
+                if self.mirostat_eta.is_nan() || self.mirostat_eta <= 0.0 {
+                    return Err(OptionsError::OutOfRange {
+                        name: "mirostat_eta",
+                        value: self.mirostat_eta,
+                    });
+                }
+
+                // both of these bypass the sampling chain mirostat is part of
+                if self.temperature <= 0.0 {
+                    return Err(OptionsError::ConflictingMirostat(
+                        "has no effect with greedy sampling (temperature 0)",
+                    ));
+                }
+
+                if self.mode != GenerationMode::Sampling {
+                    return Err(OptionsError::ConflictingMirostat(
+                        "can't be used with beam search",
+                    ));
+                }
+            }
+            mirostat => return Err(OptionsError::InvalidMirostat(mirostat)),
+        }
+
+        parse_logit_bias(&self.logit_bias).map(|_| ())
     }
+}
 
-    pub fn enable_penalize_nl(&mut self) {
-        self.penalize_nl = true;
+// the token id and the signed bias, checked against the vocabulary by LLama::validate_predict
+pub(crate) fn parse_logit_bias(logit_bias: &str) -> Result<Option<(i32, f32)>, OptionsError> {
+    if logit_bias.is_empty() {
+        return Ok(None);
     }
 
-    pub fn set_logit_bias(&mut self, logit_bias: String) {
-        self.logit_bias = logit_bias;
+    let invalid = || OptionsError::InvalidLogitBias(logit_bias.to_string());
+
+    let bias = logit_bias.trim();
+
+    // skip the first character so a negative token id is rejected below instead of being read as
+    // the bias sign
+    let (sign, negative) = bias
+        .char_indices()
+        .skip(1)
+        .find(|(_, c)| *c == '+' || *c == '-')
+        .map(|(i, c)| (i, c == '-'))
+        .ok_or_else(invalid)?;
+
+    let token = bias[..sign].trim().parse::<i32>().map_err(|_| invalid())?;
+    if token < 0 {
+        return Err(invalid());
     }
 
-    pub fn ignore_eos(&mut self) {
-        self.ignore_eos = true;
+    // values past f32::MAX parse as infinity
+    let value = bias[sign + 1..]
+        .trim()
+        .parse::<f32>()
+        .map_err(|_| invalid())?;
+    if !value.is_finite() {
+        return Err(invalid());
     }
+
+    Ok(Some((token, if negative { -value } else { value })))
 }
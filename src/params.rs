@@ -0,0 +1,237 @@
+use std::{
+    error::Error,
+    ffi::{c_char, c_void, CString},
+    ptr,
+};
+
+use crate::{
+    llama_allocate_params, llama_binding_params, llama_free_params,
+    options::{parse_logit_bias, PredictOptions},
+};
+
+// a gpt_params allocated by the binding, freed when dropped
+pub(crate) struct Params(pub(crate) *mut c_void);
+
+impl Params {
+    // the binding copies every string, they only need to outlive the llama_allocate_params call
+    pub(crate) fn new(prompt: &str, opts: &PredictOptions) -> Result<Self, Box<dyn Error>> {
+        let prompt = CString::new(prompt)?;
+        let (logit_bias_token, logit_bias) =
+            parse_logit_bias(&opts.logit_bias)?.unwrap_or((-1, 0.0));
+        let session_file = CString::new(opts.path_prompt_cache.as_str())?;
+
+        let stop_prompts = opts
+            .stop_prompts
+            .iter()
+            .map(|prompt| CString::new(prompt.as_str()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let antiprompt: Vec<*const c_char> = stop_prompts.iter().map(|s| s.as_ptr()).collect();
+
+        let binding_params = llama_binding_params {
+            prompt: prompt.as_ptr(),
+            seed: opts.seed,
+            threads: opts.threads,
+            n_predict: opts.n_predict(),
+            top_k: opts.top_k,
+            top_p: opts.top_p,
+            temp: opts.temperature,
+            repeat_penalty: opts.penalty,
+            repeat_last_n: opts.repeat,
+            ignore_eos: opts.ignore_eos,
+            memory_f16: opts.f16_kv,
+            n_batch: opts.batch,
+            n_keep: opts.n_keep,
+            antiprompt: if antiprompt.is_empty() {
+                ptr::null()
+            } else {
+                antiprompt.as_ptr()
+            },
+            antiprompt_count: antiprompt.len() as i32,
+            tfs_z: opts.tail_free_sampling_z,
+            typical_p: opts.typical_p,
+            frequency_penalty: opts.frequency_penalty,
+            presence_penalty: opts.presence_penalty,
+            mirostat: opts.mirostat,
+            mirostat_eta: opts.mirostat_eta,
+            mirostat_tau: opts.mirostat_tau,
+            penalize_nl: opts.penalize_nl,
+            logit_bias_token,
+            logit_bias,
+            session_file: session_file.as_ptr(),
+            prompt_cache_all: opts.prompt_cache_all,
+            mlock: opts.m_lock,
+            mmap: opts.m_map,
+            prompt_cache_ro: opts.prompt_cache_ro,
+        };
+
+        Ok(Self(unsafe { llama_allocate_params(&binding_params) }))
+    }
+
+    // params for the sampling chain only, the prompt and stop prompts are handled on the rust side
+    pub(crate) fn sampling(opts: &PredictOptions) -> Result<Self, Box<dyn Error>> {
+        Self::new(
+            "",
+            &PredictOptions {
+                stop_prompts: vec![],
+                path_prompt_cache: String::new(),
+                prompt_cache_all: false,
+                prompt_cache_ro: true,
+                ..opts.clone()
+            },
+        )
+    }
+}
+
+impl Drop for Params {
+    fn drop(&mut self) {
+        unsafe {
+            llama_free_params(self.0);
+        }
+    }
+}
+
+// these only check that every Params the tests create is freed again, anything the binding
+// leaks otherwise needs a leak checker, see the README
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::*;
+    use crate::{llama_binding_live_params, test_model};
+
+    fn opts() -> PredictOptions {
+        PredictOptions {
+            stop_prompts: vec!["User:".to_string(), "\n\n".to_string()],
+            logit_bias: "15043+1".to_string(),
+            path_prompt_cache: "session.bin".to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn live_params() -> i32 {
+        unsafe { llama_binding_live_params() }
+    }
+
+    #[test]
+    fn allocate_and_free() {
+        let live = live_params();
+
+        let params = [
+            Params::new("Hello", &opts()).unwrap(),
+            Params::new("", &PredictOptions::default()).unwrap(),
+            Params::sampling(&opts()).unwrap(),
+        ];
+
+        assert_eq!(live_params(), live + 3);
+        assert!(params.iter().all(|params| !params.0.is_null()));
+
+        drop(params);
+
+        assert_eq!(live_params(), live);
+    }
+
+    #[test]
+    fn rejects_nul_bytes() {
+        let live = live_params();
+        let nul = "a\0b".to_string();
+
+        assert!(Params::new(&nul, &opts()).is_err());
+
+        let mut with_nul = opts();
+        with_nul.stop_prompts.push(nul.clone());
+        assert!(Params::new("Hello", &with_nul).is_err());
+
+        let with_nul = PredictOptions {
+            path_prompt_cache: nul,
+            ..opts()
+        };
+        assert!(Params::new("Hello", &with_nul).is_err());
+
+        assert_eq!(live_params(), live);
+    }
+
+    #[test]
+    fn rejects_invalid_logit_bias() {
+        let live = live_params();
+
+        for logit_bias in [
+            "-1+1",
+            "15043",
+            "15043+",
+            "15043+1e40",
+            "15043-inf",
+            "15043+NaN",
+        ] {
+            let opts = PredictOptions {
+                logit_bias: logit_bias.to_string(),
+                ..opts()
+            };
+            assert!(Params::new("Hello", &opts).is_err(), "{}", logit_bias);
+        }
+
+        assert_eq!(
+            parse_logit_bias(" 15043 - 1.5 ").unwrap(),
+            Some((15043, -1.5))
+        );
+        assert_eq!(live_params(), live);
+    }
+
+    #[test]
+    #[ignore = "needs a model, set LLAMA_TEST_MODEL"]
+    fn rejects_logit_bias_outside_vocab() {
+        let llama = test_model();
+        let opts = PredictOptions {
+            logit_bias: format!("{}+1", llama.n_vocab()),
+            ..Default::default()
+        };
+        assert!(llama.predict("Hello".to_string(), opts).is_err());
+    }
+
+    #[test]
+    #[ignore = "needs a model, set LLAMA_TEST_MODEL"]
+    fn predict_frees_params() {
+        let llama = test_model();
+        let live = live_params();
+
+        let opts = PredictOptions {
+            max_tokens: Some(4),
+            ..Default::default()
+        };
+
+        for _ in 0..3 {
+            llama.predict("Hello".to_string(), opts.clone()).unwrap();
+            llama.eval("Hello".to_string(), &opts).unwrap();
+        }
+
+        assert_eq!(live_params(), live);
+    }
+
+    #[test]
+    #[ignore = "needs a model, set LLAMA_TEST_MODEL"]
+    fn predict_failure() {
+        let llama = test_model();
+        let live = live_params();
+
+        let session = env::temp_dir().join(format!("llama-test-session-{}", std::process::id()));
+        fs::write(&session, b"not a session file").unwrap();
+
+        let opts = PredictOptions {
+            max_tokens: Some(4),
+            path_prompt_cache: session.to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+
+        assert!(llama.predict("Hello".to_string(), opts).is_err());
+        assert!(llama
+            .load_state(session.to_string_lossy().into_owned())
+            .is_err());
+        assert!(llama
+            .save_state("/nonexistent/llama-test-state".to_string())
+            .is_err());
+
+        fs::remove_file(session).unwrap();
+
+        assert_eq!(live_params(), live);
+    }
+}
@@ -0,0 +1,58 @@
+use std::{
+    error::Error,
+    ffi::{c_int, c_void, CString},
+    path::Path,
+    ptr,
+};
+
+use crate::{llama_binding_quantize, options::QuantizeOptions};
+
+type ProgressCallback = Box<dyn Fn(usize, usize) + Send + 'static>;
+
+pub fn quantize<P: AsRef<Path>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+    opts: QuantizeOptions,
+) -> Result<(), Box<dyn Error>> {
+    let input = input.as_ref();
+    let output = output.as_ref();
+
+    if !input.is_file() {
+        return Err(format!("Model {} does not exist", input.display()).into());
+    }
+
+    let input_cstr = CString::new(input.to_string_lossy().into_owned())?;
+    let output_cstr = CString::new(output.to_string_lossy().into_owned())?;
+
+    let progress = match &opts.progress_callback {
+        Some(callback) => callback as *const ProgressCallback as *mut c_void,
+        None => ptr::null_mut(),
+    };
+
+    let result = unsafe {
+        llama_binding_quantize(
+            input_cstr.as_ptr(),
+            output_cstr.as_ptr(),
+            opts.ftype as i32,
+            opts.threads,
+            opts.allow_requantize,
+            opts.quantize_output_tensor,
+            opts.only_copy,
+            progress,
+        )
+    };
+
+    if result != 0 {
+        return Err(format!("Failed to quantize {}", input.display()).into());
+    }
+
+    Ok(())
+}
+
+// only called on the thread running quantize, progress is read from llama.cpp's log
+#[no_mangle]
+extern "C" fn quantizeProgressCallback(progress: *mut c_void, current: c_int, total: c_int) {
+    let callback = unsafe { &*(progress as *const ProgressCallback) };
+
+    callback(current.max(0) as usize, total.max(0) as usize);
+}
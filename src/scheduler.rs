@@ -0,0 +1,402 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    sync::mpsc::{self, Receiver, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    cancel::CancelToken,
+    context::{Batch, Context},
+    generation::{base_seed, Completion, Sequence, StopReason},
+    metrics::{self, ErrorKind},
+    options::{GenerationMode, PredictOptions},
+    params::Params,
+    LLama,
+};
+
+#[derive(Debug, Clone)]
+pub struct SchedulerOptions {
+    pub max_sequences: usize,
+    // prompt tokens a single request may ingest per step, so long prompts don't stall the
+    // requests that are already generating
+    pub prompt_chunk_size: usize,
+    pub threads: i32,
+}
+
+impl Default for SchedulerOptions {
+    fn default() -> Self {
+        Self {
+            max_sequences: 4,
+            prompt_chunk_size: 128,
+            threads: 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Token(String),
+    Done(Completion),
+    Error(String),
+}
+
+pub struct RequestHandle {
+    events: Receiver<Event>,
+}
+
+impl Iterator for RequestHandle {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.events.recv().ok()
+    }
+}
+
+impl RequestHandle {
+    pub fn wait(self) -> Result<Completion, Box<dyn Error>> {
+        for event in self {
+            match event {
+                Event::Token(_) => {}
+                Event::Done(completion) => return Ok(completion),
+                Event::Error(err) => return Err(err.into()),
+            }
+        }
+
+        Err("Scheduler stopped before the request completed".into())
+    }
+}
+
+struct Request {
+    text: String,
+    opts: PredictOptions,
+    events: Sender<Event>,
+}
+
+// llama.cpp contexts may move between threads as long as only one uses them at a time, LLama
+// can't be cloned so once moved into the scheduler the worker holds the only handle
+struct Model(LLama);
+
+unsafe impl Send for Model {}
+
+// dropping the scheduler waits for the decode in progress, every request that hasn't completed by
+// then, queued or generating, gets an error
+pub struct Scheduler {
+    requests: Option<Sender<Request>>,
+    shutdown: CancelToken,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Scheduler {
+    pub fn new(llama: LLama, opts: SchedulerOptions) -> Result<Self, Box<dyn Error>> {
+        if opts.max_sequences == 0 {
+            return Err("max_sequences must be at least 1".into());
+        }
+
+        if opts.prompt_chunk_size == 0 {
+            return Err("prompt_chunk_size must be at least 1".into());
+        }
+
+        // every step decodes one token per generating sequence in a single batch
+        if opts.max_sequences > llama.n_batch as usize {
+            return Err(format!(
+                "{} sequences don't fit in the batch size {}",
+                opts.max_sequences, llama.n_batch
+            )
+            .into());
+        }
+
+        let (requests, receiver) = mpsc::channel();
+        let model = Model(llama);
+        let shutdown = CancelToken::new();
+        let stopped = shutdown.clone();
+
+        let worker = thread::spawn(move || {
+            let model = model;
+            run(&model.0, &opts, receiver, &stopped);
+        });
+
+        Ok(Self {
+            requests: Some(requests),
+            shutdown,
+            worker: Some(worker),
+        })
+    }
+
+    pub fn submit(&self, text: String, opts: PredictOptions) -> RequestHandle {
+        let (events, receiver) = mpsc::channel();
+
+        let request = Request { text, opts, events };
+
+        // the queue is only closed on drop, a failed send means the worker is gone
+        if let Some(requests) = &self.requests {
+            if let Err(mpsc::SendError(request)) = requests.send(request) {
+                let _ = request
+                    .events
+                    .send(Event::Error("Scheduler is not running".into()));
+            }
+        }
+
+        RequestHandle { events: receiver }
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        // the worker checks for shutdown between steps, closing the queue wakes it up when idle
+        self.shutdown.cancel();
+        self.requests.take();
+
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+struct Slot {
+    request: Request,
+    params: Params,
+    prompt: Vec<i32>,
+    n_ingested: usize,
+    n_past: i32,
+    sequence: Sequence,
+    // whether the last batch holds the logits the next token is sampled from
+    ready: bool,
+}
+
+fn admit(llama: &LLama, request: Request, n_ctx_slot: i32) -> Result<Slot, Box<dyn Error>> {
+    let unsupported = if request.opts.mode != GenerationMode::Sampling {
+        Some("The scheduler does not support beam search")
+    } else if request.opts.n_completions != 1 {
+        Some("The scheduler only supports sampling a single completion")
+    } else if request.opts.prompt_lookup.is_some() {
+        Some("The scheduler does not support prompt lookup decoding")
+    } else {
+        None
+    };
+
+    if let Some(err) = unsupported {
+        metrics::record_error(&llama.name, ErrorKind::InvalidOptions);
+        return Err(err.into());
+    }
+
+    llama.validate_predict(&request.opts)?;
+
+    let prompt = llama.tokenize_prompt(&request.text)?;
+
+    if prompt.len() as i32 >= n_ctx_slot {
+        metrics::record_error(&llama.name, ErrorKind::Predict);
+        return Err("Prompt does not fit in a scheduler slot".into());
+    }
+
+    Ok(Slot {
+        params: Params::sampling(&request.opts)?,
+        sequence: Sequence::new(&prompt, base_seed(request.opts.seed), &request.opts, 0),
+        prompt,
+        n_ingested: 0,
+        n_past: 0,
+        ready: false,
+        request,
+    })
+}
+
+fn run(
+    llama: &LLama,
+    opts: &SchedulerOptions,
+    requests: Receiver<Request>,
+    shutdown: &CancelToken,
+) {
+    let mut ctx = Context::new(llama);
+    ctx.set_n_threads(opts.threads);
+    ctx.clear_kv_cache();
+
+    let eos = llama.token_eos();
+    let n_batch = ctx.n_batch();
+
+    // every slot gets an equal share of the KV cache, so a batch can never run out of cells
+    let n_ctx_slot = (ctx.n_ctx() / opts.max_sequences) as i32;
+
+    let mut slots: Vec<Option<Slot>> = (0..opts.max_sequences).map(|_| None).collect();
+    let mut pending: VecDeque<Request> = VecDeque::new();
+    let mut connected = true;
+    let mut next_ingest = 0;
+    let mut batch = Batch::with_capacity(n_batch);
+
+    loop {
+        if slots.iter().all(Option::is_none) && pending.is_empty() {
+            if !connected {
+                break;
+            }
+
+            match requests.recv() {
+                Ok(request) => pending.push_back(request),
+                Err(_) => break,
+            }
+        }
+
+        loop {
+            match requests.try_recv() {
+                Ok(request) => pending.push_back(request),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    connected = false;
+                    break;
+                }
+            }
+        }
+
+        if shutdown.is_cancelled() {
+            let unfinished = slots
+                .drain(..)
+                .flatten()
+                .map(|slot| slot.request)
+                .chain(pending.drain(..));
+
+            for request in unfinished {
+                let _ = request.events.send(Event::Error(
+                    "Scheduler was dropped before the request completed".into(),
+                ));
+            }
+
+            break;
+        }
+
+        for (seq_id, slot) in slots.iter_mut().enumerate() {
+            while slot.is_none() {
+                let Some(request) = pending.pop_front() else {
+                    break;
+                };
+
+                let events = request.events.clone();
+
+                match admit(llama, request, n_ctx_slot) {
+                    Ok(admitted) => {
+                        ctx.kv_cache_seq_rm(seq_id as i32, -1, -1);
+                        *slot = Some(admitted);
+                    }
+                    Err(err) => {
+                        let _ = events.send(Event::Error(err.to_string()));
+                    }
+                }
+            }
+        }
+
+        batch.clear();
+
+        let mut in_batch = Vec::with_capacity(slots.len());
+
+        // sequences that are generating get one token each
+        for (seq_id, entry) in slots.iter_mut().enumerate() {
+            let Some(slot) = entry else {
+                continue;
+            };
+
+            if !slot.ready {
+                continue;
+            }
+
+            slot.ready = false;
+
+            let stop = match slot
+                .sequence
+                .step(llama, &ctx, &slot.params, &slot.request.opts, eos)
+            {
+                Some(piece) => {
+                    if slot.request.events.send(Event::Token(piece)).is_err() {
+                        // nobody is listening anymore
+                        true
+                    } else if slot.n_past + 1 >= n_ctx_slot {
+                        slot.sequence.stop_reason = Some(StopReason::Length);
+                        true
+                    } else {
+                        slot.sequence.logits_idx = batch.len();
+                        batch.add(
+                            slot.sequence.last_token(),
+                            slot.n_past,
+                            &[seq_id as i32],
+                            true,
+                        );
+                        slot.n_past += 1;
+                        in_batch.push(seq_id);
+                        false
+                    }
+                }
+                None => true,
+            };
+
+            if stop {
+                let slot = entry.take().unwrap();
+
+                metrics::record_tokens(&llama.name, slot.prompt.len(), slot.sequence.n_generated());
+
+                let _ = slot
+                    .request
+                    .events
+                    .send(Event::Done(slot.sequence.into_completion()));
+                ctx.kv_cache_seq_rm(seq_id as i32, -1, -1);
+            }
+        }
+
+        // the rest of the batch is shared between prompts round-robin, in chunks
+        let n_slots = slots.len();
+        for k in 0..n_slots {
+            let seq_id = (next_ingest + k) % n_slots;
+
+            let Some(slot) = &mut slots[seq_id] else {
+                continue;
+            };
+
+            let remaining = slot.prompt.len() - slot.n_ingested;
+            let room = n_batch - batch.len();
+
+            if remaining == 0 {
+                continue;
+            }
+
+            if room == 0 {
+                break;
+            }
+
+            let n = remaining.min(opts.prompt_chunk_size).min(room);
+
+            for p in slot.n_ingested..slot.n_ingested + n {
+                let last = p + 1 == slot.prompt.len();
+
+                if last {
+                    slot.sequence.logits_idx = batch.len();
+                }
+
+                batch.add(slot.prompt[p], p as i32, &[seq_id as i32], last);
+            }
+
+            slot.n_ingested += n;
+            slot.n_past = slot.n_ingested as i32;
+            in_batch.push(seq_id);
+        }
+
+        next_ingest = (next_ingest + 1) % n_slots;
+
+        if batch.is_empty() {
+            continue;
+        }
+
+        match ctx.decode(&batch) {
+            Ok(()) => {
+                for seq_id in in_batch {
+                    if let Some(slot) = &mut slots[seq_id] {
+                        slot.ready = slot.n_ingested == slot.prompt.len();
+                    }
+                }
+            }
+            Err(err) => {
+                for seq_id in in_batch {
+                    if let Some(slot) = slots[seq_id].take() {
+                        metrics::record_error(&llama.name, ErrorKind::Predict);
+                        let _ = slot.request.events.send(Event::Error(err.to_string()));
+                        ctx.kv_cache_seq_rm(seq_id as i32, -1, -1);
+                    }
+                }
+            }
+        }
+    }
+}
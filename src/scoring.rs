@@ -0,0 +1,206 @@
+use std::error::Error;
+
+use crate::{
+    context::{Batch, Context},
+    LLama,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenScore {
+    pub token: i32,
+    pub piece: String,
+    pub logprob: f32,
+}
+
+pub(crate) fn log_softmax(logits: &[f32], token: i32) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let sum: f64 = logits.iter().map(|&l| ((l - max) as f64).exp()).sum();
+
+    logits[token as usize] - max - sum.ln() as f32
+}
+
+impl Context<'_> {
+    // evaluates `tokens` from position 0 of sequence 0 and returns the log-probability of every
+    // token from index `from` onwards (the first token has no prefix, so it is never scored)
+    pub(crate) fn token_logprobs(
+        &mut self,
+        tokens: &[i32],
+        from: usize,
+    ) -> Result<Vec<f32>, Box<dyn Error>> {
+        if tokens.len() > self.n_ctx() {
+            return Err("Input does not fit in the context".into());
+        }
+
+        let from = from.max(1);
+        let n_batch = self.n_batch();
+
+        let mut out = Vec::with_capacity(tokens.len().saturating_sub(from));
+        let mut batch = Batch::with_capacity(n_batch);
+
+        self.clear_kv_cache();
+
+        for (n, chunk) in tokens.chunks(n_batch).enumerate() {
+            let offset = n * n_batch;
+
+            batch.clear();
+
+            for (i, &token) in chunk.iter().enumerate() {
+                // the logits at position p predict the token at p + 1
+                let next = offset + i + 1;
+                batch.add(
+                    token,
+                    (offset + i) as i32,
+                    &[0],
+                    next >= from && next < tokens.len(),
+                );
+            }
+
+            self.decode(&batch)?;
+
+            for i in 0..batch.len() {
+                if batch.has_logits(i) {
+                    out.push(log_softmax(self.logits(i), tokens[offset + i + 1]));
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+impl LLama {
+    pub fn score(&mut self, text: &str) -> Result<Vec<TokenScore>, Box<dyn Error>> {
+        let tokens = self.tokenize(text, true)?;
+
+        if tokens.len() < 2 {
+            return Err("Text is too short to score".into());
+        }
+
+        let logprobs = self.context().token_logprobs(&tokens, 1)?;
+
+        Ok(tokens[1..]
+            .iter()
+            .zip(logprobs)
+            .map(|(&token, logprob)| TokenScore {
+                token,
+                piece: self.token_to_piece(token),
+                logprob,
+            })
+            .collect())
+    }
+
+    // texts longer than the context are evaluated in windows of n_ctx tokens advanced by
+    // `stride`, each token is scored once with as much of its prefix as the window allows
+    pub fn perplexity(&mut self, text: &str, stride: usize) -> Result<f64, Box<dyn Error>> {
+        let tokens = self.tokenize(text, true)?;
+
+        if tokens.len() < 2 {
+            return Err("Text is too short to compute perplexity".into());
+        }
+
+        let mut ctx = self.context();
+        let n_ctx = ctx.n_ctx();
+
+        if stride == 0 || stride > n_ctx {
+            return Err(
+                format!("stride must be between 1 and the context size ({})", n_ctx).into(),
+            );
+        }
+
+        let mut nll = 0.0f64;
+        let mut count = 0usize;
+        let mut start = 0;
+        let mut scored_end = 1;
+
+        loop {
+            let end = (start + n_ctx).min(tokens.len());
+
+            for logprob in ctx.token_logprobs(&tokens[start..end], scored_end - start)? {
+                nll -= logprob as f64;
+                count += 1;
+            }
+
+            if end == tokens.len() {
+                break;
+            }
+
+            scored_end = end;
+            start += stride;
+        }
+
+        Ok((nll / count as f64).exp())
+    }
+
+    // scores each label as a continuation of `prompt` and normalizes over the label set, the
+    // prompt is evaluated once and shared with every label through the KV cache
+    pub fn classify(
+        &mut self,
+        prompt: &str,
+        labels: &[&str],
+    ) -> Result<Vec<(String, f32)>, Box<dyn Error>> {
+        if labels.is_empty() {
+            return Err("No labels to classify".into());
+        }
+
+        let prompt_tokens = self.tokenize(prompt, true)?;
+
+        let mut label_tokens = Vec::with_capacity(labels.len());
+        for label in labels {
+            let tokens = self.tokenize(label, false)?;
+            if tokens.is_empty() {
+                return Err(format!("Label {:?} has no tokens", label).into());
+            }
+            label_tokens.push(tokens);
+        }
+
+        let mut ctx = self.context();
+
+        let longest = label_tokens.iter().map(Vec::len).max().unwrap_or(0);
+        if prompt_tokens.len() + longest > ctx.n_ctx() {
+            return Err("Prompt and labels do not fit in the context".into());
+        }
+
+        ctx.clear_kv_cache();
+
+        let last = ctx.decode_tokens(&prompt_tokens, 0, 0)?;
+        let prompt_logits = ctx.logits(last).to_vec();
+
+        let n_past = prompt_tokens.len() as i32;
+        let mut scores = Vec::with_capacity(labels.len());
+
+        for tokens in &label_tokens {
+            let mut logprob = log_softmax(&prompt_logits, tokens[0]);
+
+            // the last label token is only ever predicted, never evaluated
+            if tokens.len() > 1 {
+                ctx.kv_cache_seq_cp(0, 1, -1, -1);
+
+                let mut batch = Batch::with_capacity(tokens.len() - 1);
+                for (i, &token) in tokens[..tokens.len() - 1].iter().enumerate() {
+                    batch.add(token, n_past + i as i32, &[1], true);
+                }
+
+                let res = ctx.decode(&batch);
+                if res.is_ok() {
+                    for i in 0..batch.len() {
+                        logprob += log_softmax(ctx.logits(i), tokens[i + 1]);
+                    }
+                }
+
+                ctx.kv_cache_seq_rm(1, -1, -1);
+                res?;
+            }
+
+            scores.push(logprob);
+        }
+
+        let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let sum: f32 = scores.iter().map(|s| (s - max).exp()).sum();
+
+        Ok(labels
+            .iter()
+            .zip(scores)
+            .map(|(label, score)| (label.to_string(), (score - max).exp() / sum))
+            .collect())
+    }
+}
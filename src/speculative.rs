@@ -0,0 +1,320 @@
+use std::error::Error;
+
+use crate::{
+    context::{Batch, Context},
+    generation::{base_seed, Generation, GenerationStats, Sequence, StopReason},
+    options::{GenerationMode, PredictOptions, PromptLookup, TokenCallback},
+    params::Params,
+    LLama,
+};
+
+pub struct SpeculativeGenerator {
+    target: LLama,
+    draft: LLama,
+    n_draft: usize,
+    compatible: bool,
+}
+
+impl SpeculativeGenerator {
+    // the draft model has to share the target's vocabulary, when it doesn't every call falls
+    // back to decoding with the target alone
+    pub fn new(target: LLama, draft: LLama, n_draft: usize) -> Result<Self, Box<dyn Error>> {
+        // the target verifies the drafted tokens and the last sampled one in a single batch
+        if n_draft + 1 > target.n_batch as usize {
+            return Err(format!(
+                "{} drafted tokens don't fit in the target's batch size {}",
+                n_draft, target.n_batch
+            )
+            .into());
+        }
+
+        let compatible = vocab_matches(&target, &draft);
+
+        Ok(Self {
+            target,
+            draft,
+            n_draft,
+            compatible,
+        })
+    }
+
+    pub fn is_speculative(&self) -> bool {
+        self.compatible && self.n_draft > 0
+    }
+
+    pub fn target(&self) -> &LLama {
+        &self.target
+    }
+
+    pub fn draft(&self) -> &LLama {
+        &self.draft
+    }
+
+    pub fn predict(&self, text: String, opts: PredictOptions) -> Result<String, Box<dyn Error>> {
+        let generation = self.generate_inner(text, opts, None)?;
+
+        Ok(generation
+            .completions
+            .into_iter()
+            .next()
+            .map(|completion| completion.text)
+            .unwrap_or_default())
+    }
+
+    pub fn generate(
+        &self,
+        text: String,
+        opts: PredictOptions,
+    ) -> Result<Generation, Box<dyn Error>> {
+        self.generate_inner(text, opts, None)
+    }
+
+    pub fn generate_with_callback(
+        &self,
+        text: String,
+        opts: PredictOptions,
+        callback: TokenCallback,
+    ) -> Result<Generation, Box<dyn Error>> {
+        self.generate_inner(text, opts, Some(callback))
+    }
+
+    fn generate_inner(
+        &self,
+        text: String,
+        opts: PredictOptions,
+        callback: Option<TokenCallback>,
+    ) -> Result<Generation, Box<dyn Error>> {
+        if !self.is_speculative()
+            || opts.mode != GenerationMode::Sampling
+            || opts.n_completions != 1
+        {
+            return self.target.generate_inner(text, opts, callback);
+        }
+
+        self.target.validate_predict(&opts)?;
+
+        let eos = self.target.token_eos();
+
+        let mut draft = Context::new(&self.draft);
+        draft.set_n_threads(opts.threads);
+        draft.clear_kv_cache();
+
+        let mut evaluated = Vec::new();
+
+        // only the target's timings are reported
+        self.target.timed(opts.debug_mode, || {
+            decode_drafted(
+                &self.target,
+                &text,
+                &opts,
+                callback.as_ref(),
+                self.n_draft,
+                |history, n| draft_greedy(&mut draft, &mut evaluated, history, n, eos),
+            )
+        })
+    }
+}
+
+// the target samples every position of a drafted run exactly as it would without a draft,
+// drafted tokens are kept for as long as they agree with what the target sampled
+pub(crate) fn decode_drafted<F>(
+    llama: &LLama,
+    text: &str,
+    opts: &PredictOptions,
+    callback: Option<&TokenCallback>,
+    n_draft: usize,
+    mut draft: F,
+) -> Result<Generation, Box<dyn Error>>
+where
+    F: FnMut(&[i32], usize) -> Result<Vec<i32>, Box<dyn Error>>,
+{
+    let prompt = llama.tokenize_prompt(text)?;
+    let eos = llama.token_eos();
+    let params = Params::sampling(opts)?;
+
+    let mut ctx = Context::new(llama);
+    ctx.set_n_threads(opts.threads);
+
+    let n_ctx = ctx.n_ctx() as i32;
+
+    if prompt.len() as i32 >= n_ctx {
+        return Err("Prompt does not fit in the context".into());
+    }
+
+    ctx.clear_kv_cache();
+
+    let last = ctx.decode_tokens(&prompt, 0, 0)?;
+
+    let mut seq = Sequence::new(&prompt, base_seed(opts.seed), opts, last);
+    let mut stats = GenerationStats::default();
+    let mut batch = Batch::with_capacity(n_draft + 1);
+
+    // the cache holds the sequence history except for the last sampled token
+    let mut n_past = prompt.len() as i32;
+
+    let piece = seq.step(llama, &ctx, &params, opts, eos);
+    let mut continuing = emit(&mut seq, piece, callback);
+
+    while continuing {
+        if n_past + 1 >= n_ctx {
+            seq.stop_reason = Some(StopReason::Length);
+            break;
+        }
+
+        let n = n_draft.min((n_ctx - n_past - 1) as usize);
+
+        let mut drafted = if n > 0 {
+            draft(seq.history(), n)?
+        } else {
+            Vec::new()
+        };
+        drafted.truncate(n);
+
+        batch.clear();
+        batch.add(seq.last_token(), n_past, &[0], true);
+
+        for (i, &token) in drafted.iter().enumerate() {
+            batch.add(token, n_past + 1 + i as i32, &[0], true);
+        }
+
+        ctx.decode(&batch)?;
+
+        let mut accepted = 0;
+
+        for i in 0..=drafted.len() {
+            seq.logits_idx = i;
+
+            let piece = seq.step(llama, &ctx, &params, opts, eos);
+            continuing = emit(&mut seq, piece, callback);
+
+            if !continuing || i == drafted.len() || seq.last_token() != drafted[i] {
+                break;
+            }
+
+            accepted += 1;
+        }
+
+        stats.drafted_tokens += drafted.len();
+        stats.accepted_tokens += accepted;
+
+        // drop whatever was evaluated past the accepted tokens
+        n_past += 1 + accepted as i32;
+        ctx.kv_cache_seq_rm(0, n_past, -1);
+    }
+
+    stats.prompt_tokens = prompt.len();
+    stats.generated_tokens = seq.n_generated();
+
+    Ok(Generation {
+        completions: vec![seq.into_completion()],
+        stats,
+        ..Default::default()
+    })
+}
+
+// the draft cache keeps whatever prefix of the history it evaluated on the previous call, only
+// the tokens after it are decoded again
+fn draft_greedy(
+    ctx: &mut Context,
+    evaluated: &mut Vec<i32>,
+    history: &[i32],
+    n: usize,
+    eos: i32,
+) -> Result<Vec<i32>, Box<dyn Error>> {
+    let n = n.min(ctx.n_ctx().saturating_sub(history.len()));
+
+    if n == 0 {
+        return Ok(Vec::new());
+    }
+
+    let n_keep = evaluated
+        .iter()
+        .zip(history)
+        .take_while(|(a, b)| a == b)
+        .count()
+        .min(history.len() - 1);
+
+    ctx.kv_cache_seq_rm(0, n_keep as i32, -1);
+    evaluated.truncate(n_keep);
+
+    let mut idx = ctx.decode_tokens(&history[n_keep..], n_keep as i32, 0)?;
+    evaluated.extend_from_slice(&history[n_keep..]);
+
+    let mut drafted = Vec::with_capacity(n);
+    let mut batch = Batch::with_capacity(1);
+
+    loop {
+        let token = argmax(ctx.logits(idx));
+        drafted.push(token);
+
+        if drafted.len() == n || token == eos {
+            break;
+        }
+
+        batch.clear();
+        batch.add(token, evaluated.len() as i32, &[0], true);
+        ctx.decode(&batch)?;
+
+        evaluated.push(token);
+        idx = 0;
+    }
+
+    Ok(drafted)
+}
+
+// proposes the tokens that followed the most recent earlier occurrence of the history's last
+// n-gram, longer n-grams are tried first
+pub(crate) fn lookup_ngram(history: &[i32], lookup: &PromptLookup, n: usize) -> Vec<i32> {
+    for size in (lookup.ngram_min.max(1)..=lookup.ngram_max).rev() {
+        if size >= history.len() {
+            continue;
+        }
+
+        let ngram = &history[history.len() - size..];
+
+        for start in (0..history.len() - size).rev() {
+            if &history[start..start + size] == ngram {
+                let from = start + size;
+                let to = (from + n).min(history.len());
+
+                return history[from..to].to_vec();
+            }
+        }
+    }
+
+    Vec::new()
+}
+
+fn emit(seq: &mut Sequence, piece: Option<String>, callback: Option<&TokenCallback>) -> bool {
+    let Some(piece) = piece else {
+        return false;
+    };
+
+    if let Some(callback) = callback {
+        if !callback(piece) {
+            seq.stop_reason = Some(StopReason::Callback);
+            return false;
+        }
+    }
+
+    true
+}
+
+fn argmax(logits: &[f32]) -> i32 {
+    logits
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(token, _)| token as i32)
+        .unwrap_or(0)
+}
+
+fn vocab_matches(target: &LLama, draft: &LLama) -> bool {
+    let n_vocab = target.n_vocab();
+
+    if n_vocab != draft.n_vocab() || target.token_eos() != draft.token_eos() {
+        return false;
+    }
+
+    (0..n_vocab as i32).all(|token| target.token_to_piece(token) == draft.token_to_piece(token))
+}
@@ -0,0 +1,86 @@
+use std::{fmt, time::Duration};
+
+use crate::{llama_binding_get_timings, llama_binding_reset_timings, LLama};
+
+// llama.cpp's performance counters, every call starts them over except for the load time
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Timings {
+    pub load: Duration,
+    pub sample: Duration,
+    pub n_sample: usize,
+    pub prompt_eval: Duration,
+    pub n_prompt_eval: usize,
+    pub eval: Duration,
+    pub n_eval: usize,
+}
+
+impl Timings {
+    pub fn sample_tokens_per_second(&self) -> f64 {
+        per_second(self.n_sample, self.sample)
+    }
+
+    pub fn prompt_eval_tokens_per_second(&self) -> f64 {
+        per_second(self.n_prompt_eval, self.prompt_eval)
+    }
+
+    pub fn eval_tokens_per_second(&self) -> f64 {
+        per_second(self.n_eval, self.eval)
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "load {:.2} ms, sample {:.2} ms / {} runs ({:.2} tokens/s), prompt eval {:.2} ms / {} tokens ({:.2} tokens/s), eval {:.2} ms / {} runs ({:.2} tokens/s)",
+            ms(self.load),
+            ms(self.sample),
+            self.n_sample,
+            self.sample_tokens_per_second(),
+            ms(self.prompt_eval),
+            self.n_prompt_eval,
+            self.prompt_eval_tokens_per_second(),
+            ms(self.eval),
+            self.n_eval,
+            self.eval_tokens_per_second(),
+        )
+    }
+}
+
+impl LLama {
+    // the timings since the last reset, which every generation does when it starts
+    pub fn timings(&self) -> Timings {
+        let timings = unsafe { llama_binding_get_timings(self.state) };
+
+        Timings {
+            load: from_ms(timings.t_load_ms),
+            sample: from_ms(timings.t_sample_ms),
+            n_sample: timings.n_sample.max(0) as usize,
+            prompt_eval: from_ms(timings.t_prompt_eval_ms),
+            n_prompt_eval: timings.n_prompt_eval.max(0) as usize,
+            eval: from_ms(timings.t_eval_ms),
+            n_eval: timings.n_eval.max(0) as usize,
+        }
+    }
+
+    pub fn reset_timings(&self) {
+        unsafe { llama_binding_reset_timings(self.state) }
+    }
+}
+
+fn per_second(n_tokens: usize, duration: Duration) -> f64 {
+    if duration.is_zero() {
+        return 0.0;
+    }
+
+    n_tokens as f64 / duration.as_secs_f64()
+}
+
+fn from_ms(ms: f64) -> Duration {
+    Duration::from_secs_f64(ms.max(0.0) / 1000.0)
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}